@@ -0,0 +1,34 @@
+//! Records the ashfire version the bot is built with, as locked in `Cargo.lock`.
+
+use std::fs;
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    let version = fs::read_to_string("Cargo.lock")
+        .ok()
+        .and_then(|lock| ashfire_version(&lock))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=ASHFIRE_VERSION={version}");
+}
+
+/// The locked version, with the commit it was fetched at since it comes from git.
+fn ashfire_version(lock: &str) -> Option<String> {
+    let package = lock
+        .split("[[package]]")
+        .find(|package| package.lines().any(|line| line == "name = \"ashfire\""))?;
+
+    let version = field(package, "version")?;
+    match field(package, "source").and_then(|source| source.rsplit_once('#')) {
+        Some((_, commit)) => Some(format!("{version} ({})", &commit[..commit.len().min(7)])),
+        None => Some(version.to_string()),
+    }
+}
+
+fn field<'a>(package: &'a str, key: &str) -> Option<&'a str> {
+    package.lines().find_map(|line| {
+        line.strip_prefix(key)?
+            .strip_prefix(" = \"")?
+            .strip_suffix('"')
+    })
+}
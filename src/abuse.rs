@@ -0,0 +1,146 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use serenity::model::prelude::UserId;
+
+use crate::runtime::{CompileTimeout, MemoryLimit, TimedOut};
+
+/// Time over which resource limit hits are counted.
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Hits within the window that get a user muted.
+const THRESHOLD: usize = 5;
+const COOLDOWN: Duration = Duration::from_secs(30 * 60);
+
+/// Users whose programs keep exhausting the shared resources, who are ignored for a while.
+#[derive(Default)]
+pub struct Abuse {
+    hits: HashMap<UserId, VecDeque<Instant>>,
+    muted: HashMap<UserId, Instant>,
+}
+
+impl Abuse {
+    /// Records a run that hit a resource limit, returning whether it got the user muted.
+    pub fn hit(&mut self, user: UserId) -> bool {
+        self.hit_at(user, Instant::now())
+    }
+
+    fn hit_at(&mut self, user: UserId, now: Instant) -> bool {
+        let hits = self.hits.entry(user).or_default();
+        hits.retain(|&hit| now.duration_since(hit) < WINDOW);
+        hits.push_back(now);
+
+        if hits.len() < THRESHOLD {
+            return false;
+        }
+
+        self.hits.remove(&user);
+        self.muted.insert(user, now + COOLDOWN);
+        true
+    }
+
+    pub fn is_muted(&mut self, user: UserId) -> bool {
+        self.is_muted_at(user, Instant::now())
+    }
+
+    fn is_muted_at(&mut self, user: UserId, now: Instant) -> bool {
+        match self.muted.get(&user) {
+            Some(until) if now < *until => true,
+            Some(_) => {
+                self.muted.remove(&user);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Lifts a mute, returning whether there was one.
+    pub fn unmute(&mut self, user: UserId) -> bool {
+        self.hits.remove(&user);
+        self.muted.remove(&user).is_some()
+    }
+}
+
+/// Whether a failure comes from exhausting a limit rather than from a mistake in the code.
+pub fn is_resource_limit(err: &Error) -> bool {
+    err.downcast_ref::<TimedOut>().is_some()
+        || err.downcast_ref::<CompileTimeout>().is_some()
+        || err.downcast_ref::<MemoryLimit>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: UserId = UserId(1);
+
+    #[test]
+    fn mutes_at_the_threshold() {
+        let mut abuse = Abuse::default();
+        let now = Instant::now();
+        for _ in 1..THRESHOLD {
+            assert!(!abuse.hit_at(USER, now));
+        }
+        assert!(!abuse.is_muted_at(USER, now));
+
+        assert!(abuse.hit_at(USER, now));
+        assert!(abuse.is_muted_at(USER, now));
+        assert!(!abuse.is_muted_at(UserId(2), now));
+    }
+
+    #[test]
+    fn forgets_hits_once_muted() {
+        let mut abuse = Abuse::default();
+        let now = Instant::now();
+        for _ in 0..THRESHOLD {
+            abuse.hit_at(USER, now);
+        }
+        assert!(!abuse.hits.contains_key(&USER));
+
+        // The mute is over, and it takes as many hits as the first time to get another
+        let later = now + COOLDOWN;
+        assert!(!abuse.is_muted_at(USER, later));
+        assert!(!abuse.hit_at(USER, later));
+    }
+
+    #[test]
+    fn counts_hits_within_the_window_only() {
+        let mut abuse = Abuse::default();
+        let now = Instant::now();
+        for _ in 1..THRESHOLD {
+            abuse.hit_at(USER, now);
+        }
+
+        assert!(!abuse.hit_at(USER, now + WINDOW));
+        assert_eq!(abuse.hits[&USER].len(), 1);
+    }
+
+    #[test]
+    fn counts_exhausted_limits_only() {
+        let timed_out = TimedOut {
+            after: Duration::from_secs(5),
+            output: Vec::new(),
+        };
+        assert!(is_resource_limit(&timed_out.into()));
+        assert!(is_resource_limit(
+            &CompileTimeout(Duration::from_secs(10)).into()
+        ));
+        assert!(is_resource_limit(&MemoryLimit { pages: 1024 }.into()));
+        assert!(!is_resource_limit(&anyhow::anyhow!("stack underflow")));
+    }
+
+    #[test]
+    fn unmutes_only_muted_users() {
+        let mut abuse = Abuse::default();
+        assert!(!abuse.unmute(USER));
+
+        for _ in 0..THRESHOLD {
+            abuse.hit(USER);
+        }
+        assert!(abuse.unmute(USER));
+        assert!(!abuse.is_muted(USER));
+        assert!(!abuse.unmute(USER));
+    }
+}
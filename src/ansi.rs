@@ -0,0 +1,111 @@
+//! Handling of ANSI escape sequences in program output.
+
+const ESC: char = '\x1b';
+const BEL: char = '\x07';
+
+/// Removes every escape sequence, leaving plain text.
+pub fn strip(text: &str) -> String {
+    filter(text, |_| None)
+}
+
+/// Keeps the SGR sequences that Discord renders in `ansi` code blocks, removing the rest.
+pub fn sanitize(text: &str) -> String {
+    filter(text, |params| {
+        let mut codes = params
+            .split(';')
+            .map(|code| if code.is_empty() { "0" } else { code });
+        let mut kept = Vec::new();
+
+        while let Some(code) = codes.next() {
+            match code {
+                // Extended colors take their index or RGB components as parameters of their
+                // own, which must not be read as codes
+                "38" | "48" => {
+                    let components = match codes.next() {
+                        Some("5") => 1,
+                        Some("2") => 3,
+                        _ => 0,
+                    };
+                    codes.by_ref().take(components).for_each(drop);
+                }
+                code if is_supported(code) => kept.push(code),
+                _ => {}
+            }
+        }
+
+        (!kept.is_empty()).then(|| format!("{ESC}[{}m", kept.join(";")))
+    })
+}
+
+/// Reset, bold, underline and the 8 basic foreground and background colors.
+fn is_supported(code: &str) -> bool {
+    matches!(code.parse::<u8>(), Ok(0 | 1 | 4 | 30..=37 | 40..=47))
+}
+
+/// Rebuilds `text`, replacing each SGR sequence with what `sgr` returns for its parameters.
+/// Any other escape sequence is dropped.
+fn filter(text: &str, sgr: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // Control sequence, ending at its first byte in the `@` to `~` range
+            Some('[') => {
+                let mut params = String::new();
+                let end = chars.by_ref().find(|&c| {
+                    let end = ('@'..='~').contains(&c);
+                    if !end {
+                        params.push(c);
+                    }
+                    end
+                });
+
+                if let Some(replacement) = end.filter(|&c| c == 'm').and_then(|_| sgr(&params)) {
+                    out.push_str(&replacement);
+                }
+            }
+            // Operating system command, ending with a bell or a string terminator
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == BEL || (c == ESC && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_basic_sgr_sequences() {
+        assert_eq!(sanitize("\x1b[1;31mred\x1b[0m"), "\x1b[1;31mred\x1b[0m");
+        assert_eq!(sanitize("\x1b[mplain"), "\x1b[0mplain");
+    }
+
+    #[test]
+    fn drops_extended_colors_as_a_whole() {
+        // The 4 is a palette index, not underline
+        assert_eq!(sanitize("\x1b[38;5;4mx"), "x");
+        assert_eq!(sanitize("\x1b[1;38;2;255;0;4;4mx"), "\x1b[1;4mx");
+        assert_eq!(sanitize("\x1b[48;5;1;32mx"), "\x1b[32mx");
+    }
+
+    #[test]
+    fn drops_other_escape_sequences() {
+        assert_eq!(sanitize("\x1b[2Jclear\x1b]0;title\x07"), "clear");
+        assert_eq!(strip("\x1b[1;31mred\x1b[0m"), "red");
+    }
+}
@@ -0,0 +1,40 @@
+use anyhow::{Context, Result};
+use serenity::model::channel::Attachment;
+
+use crate::content;
+
+/// File extensions taken as source code.
+const EXTENSIONS: [&str; 3] = ["fire", "forth", "txt"];
+
+/// The source file attached to a message, if there is exactly one.
+pub fn source_file(attachments: &[Attachment]) -> Option<&Attachment> {
+    let mut sources = attachments
+        .iter()
+        .filter(|attachment| is_source(&attachment.filename));
+    let source = sources.next()?;
+    sources.next().is_none().then_some(source)
+}
+
+fn is_source(filename: &str) -> bool {
+    filename
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+pub async fn download(attachment: &Attachment) -> Result<String> {
+    let content_type = attachment.content_type.as_deref();
+    content::check("Attachment", content_type, attachment.size)?;
+
+    let bytes = attachment
+        .download()
+        .await
+        .with_context(|| "Could not download the attachment")?;
+    content::decode("Attachment", bytes)
+}
+
+/// Appends `source` to a message as a code block, fenced so that nothing inside can close it.
+pub fn with_source(content: &str, source: &str) -> String {
+    let longest = source.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{content}\n{fence}\n{source}\n{fence}")
+}
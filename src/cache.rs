@@ -0,0 +1,138 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use wasmtime::Module;
+
+const CAPACITY: usize = 128;
+
+/// The result of compiling a snippet, shared by every evaluation of the same source.
+pub struct Compiled {
+    pub key: u64,
+    pub wat: Vec<u8>,
+    pub wasm_size: usize,
+    pub module: Module,
+    /// How long each step of the first compilation took.
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+/// Content hash of a snippet, used as its cache key.
+///
+/// Keys are shown to users as 16 hex digits, see [`parse_key`].
+pub fn key(source: &str, name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub fn parse_key(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+}
+
+/// Bounded cache of compiled modules, evicting the oldest entries first.
+#[derive(Default)]
+pub struct ModuleCache {
+    entries: HashMap<u64, Arc<Compiled>>,
+    order: VecDeque<u64>,
+}
+
+impl ModuleCache {
+    pub fn get(&self, key: u64) -> Option<Arc<Compiled>> {
+        self.entries.get(&key).cloned()
+    }
+
+    pub fn remove(&mut self, key: u64) -> Option<Arc<Compiled>> {
+        self.order.retain(|&entry| entry != key);
+        self.entries.remove(&key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Inserts a module, returning how many older ones were evicted to make room.
+    pub fn insert(&mut self, compiled: Arc<Compiled>) -> usize {
+        let key = compiled.key;
+        if self.entries.insert(key, compiled).is_some() {
+            return 0;
+        }
+
+        let mut evicted = 0;
+        self.order.push_back(key);
+        while self.order.len() > CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+                evicted += 1;
+            }
+        }
+
+        evicted
+    }
+}
+
+/// Counters of how well the module cache is doing.
+#[derive(Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self, count: usize) {
+        self.evictions.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn describe(&self, size: usize) -> String {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        let rate = if lookups == 0 {
+            0.0
+        } else {
+            hits as f64 * 100.0 / lookups as f64
+        };
+
+        [
+            format!("hits      {hits} ({rate:.1}%)"),
+            format!("misses    {misses}"),
+            format!("evictions {}", self.evictions.load(Ordering::Relaxed)),
+            format!("size      {size}/{CAPACITY}"),
+        ]
+        .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{examples, runtime::Runtime};
+
+    #[test]
+    fn editing_back_reuses_the_first_compilation() {
+        let runtime = Runtime::new().unwrap();
+        let first = runtime.compile(examples::HELLO, "test").unwrap();
+        runtime.compile(examples::FIBONACCI, "test").unwrap();
+        let third = runtime.compile(examples::HELLO, "test").unwrap();
+
+        let stats = runtime.stats();
+        assert_eq!(stats.hits.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.misses.load(Ordering::Relaxed), 2);
+        assert!(Arc::ptr_eq(&first, &third));
+    }
+}
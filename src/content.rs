@@ -0,0 +1,72 @@
+use anyhow::{bail, Context, Result};
+
+/// Largest piece of source fetched, bigger ones are refused.
+pub const MAX_SIZE: u64 = 64 * 1024;
+
+/// Checks that a resource is worth fetching as source code, given what is known of it
+/// beforehand. `kind` names the resource in the error.
+pub fn check(kind: &str, content_type: Option<&str>, size: u64) -> Result<()> {
+    if let Some(content_type) = content_type {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        if !is_text(essence) {
+            bail!("Expected text, got {essence}");
+        }
+    }
+
+    if size > MAX_SIZE {
+        bail!("{kind} too large: {size} > {MAX_SIZE} bytes");
+    }
+
+    Ok(())
+}
+
+/// Turns the fetched bytes into source, checking them again since their size may not have
+/// been known upfront.
+pub fn decode(kind: &str, bytes: Vec<u8>) -> Result<String> {
+    check(kind, None, bytes.len() as u64)?;
+    String::from_utf8(bytes).with_context(|| format!("{kind} is not valid UTF-8 text"))
+}
+
+/// Files of unknown type are sent as `application/octet-stream`, so those are left to the
+/// UTF-8 check.
+fn is_text(essence: &str) -> bool {
+    essence.starts_with("text/") || essence == "application/octet-stream"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_text() {
+        assert!(check("Gist", Some("text/plain; charset=utf-8"), 10).is_ok());
+        assert!(check("Gist", None, MAX_SIZE).is_ok());
+    }
+
+    #[test]
+    fn refuses_other_types() {
+        let err = check("Attachment", Some("image/png"), 10).unwrap_err();
+        assert_eq!(err.to_string(), "Expected text, got image/png");
+    }
+
+    #[test]
+    fn leaves_unknown_types_to_the_utf8_check() {
+        assert!(check("Attachment", Some("application/octet-stream"), 10).is_ok());
+        assert_eq!(decode("Attachment", b"1 2 +".to_vec()).unwrap(), "1 2 +");
+
+        let err = decode("Attachment", vec![0xff, 0xfe]).unwrap_err();
+        assert_eq!(err.to_string(), "Attachment is not valid UTF-8 text");
+    }
+
+    #[test]
+    fn refuses_large_sources() {
+        let err = check("Gist", Some("text/plain"), MAX_SIZE + 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Gist too large: {} > {MAX_SIZE} bytes", MAX_SIZE + 1)
+        );
+
+        let bytes = vec![b' '; MAX_SIZE as usize + 1];
+        assert!(decode("Attachment", bytes).is_err());
+    }
+}
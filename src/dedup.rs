@@ -0,0 +1,38 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serenity::model::{prelude::MessageId, Timestamp};
+
+const WINDOW: Duration = Duration::from_secs(60);
+const CAPACITY: usize = 512;
+
+/// Identifies one revision of a message: redelivered events share it, real edits don't.
+type Key = (MessageId, Option<Timestamp>);
+
+/// Message events handled recently, so that gateway redeliveries are evaluated only once.
+#[derive(Default)]
+pub struct Recent {
+    seen: HashMap<Key, Instant>,
+}
+
+impl Recent {
+    /// Records an event, returning `false` if it was already seen within the window.
+    pub fn first_time(&mut self, id: MessageId, edited: Option<Timestamp>) -> bool {
+        self.seen.retain(|_, at| at.elapsed() < WINDOW);
+
+        if self.seen.len() >= CAPACITY {
+            let oldest = self
+                .seen
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(key, _)| *key);
+            if let Some(key) = oldest {
+                self.seen.remove(&key);
+            }
+        }
+
+        self.seen.insert((id, edited), Instant::now()).is_none()
+    }
+}
@@ -0,0 +1,25 @@
+pub const HELLO: &str = r#"import std
+
+proc main in
+  "Hello, World!\n" puts
+end
+"#;
+
+pub const FIBONACCI: &str = r#"import std
+
+proc main in
+  0 1 while over 1000 < do
+    over print
+    swap over +
+  end drop drop
+end
+"#;
+
+pub const EXAMPLES: &[(&str, &str)] = &[("hello", HELLO), ("fibonacci", FIBONACCI)];
+
+pub fn get(name: &str) -> Option<&'static str> {
+    EXAMPLES
+        .iter()
+        .find(|(example, _)| *example == name)
+        .map(|(_, source)| *source)
+}
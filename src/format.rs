@@ -0,0 +1,554 @@
+use std::{borrow::Cow, time::Duration};
+
+use anyhow::{bail, Error, Result};
+use serenity::model::prelude::{ChannelId, GuildId, MessageId};
+use wasmtime::{Trap, WasmBacktrace};
+
+use crate::{
+    ansi, i18n,
+    options::{Color, EvalOptions},
+    runtime::{Cancelled, PartialOutput, TimedOut},
+    settings::GuildSettings,
+    Evaluation, Stats,
+};
+
+/// Discord's maximum message length.
+pub const MESSAGE_LIMIT: usize = 2000;
+/// Discord's maximum length of an embed field's value.
+const FIELD_LIMIT: usize = 1024;
+
+const TRUNCATED: &str = "\n… (output truncated)";
+const MAX_FRAMES: usize = 10;
+/// Differing lines shown when comparing two outputs.
+const MAX_DIFF_LINES: usize = 5;
+/// Output shown from a program that timed out or crashed.
+const PARTIAL_OUTPUT: usize = 1000;
+/// How far back from the limit a cut may move to land on a line break.
+const LINE_TOLERANCE: usize = 200;
+/// Longest template a guild may set, leaving the rest of a message to the output.
+const MAX_TEMPLATE: usize = 400;
+
+/// Wrappers around a reply's output, with `{output}`, `{time}` and `{status}` placeholders.
+///
+/// Unset templates use the default wording, in the guild's locale.
+#[derive(Clone, Default)]
+pub struct Templates {
+    pub result: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Templates {
+    pub fn result(&self, locale: &str) -> String {
+        self.result
+            .clone()
+            .unwrap_or_else(|| format!("{}\n{{output}}", i18n::text(locale, "result_header")))
+    }
+
+    pub fn error(&self, locale: &str) -> String {
+        self.error
+            .clone()
+            .unwrap_or_else(|| format!("{}\n{{output}}", i18n::text(locale, "error_header")))
+    }
+}
+
+/// Rejects templates that would not show the output at all, or leave it too little room.
+pub fn validate_template(template: &str) -> Result<()> {
+    if !template.contains("{output}") {
+        bail!("A template must contain the `{{output}}` placeholder");
+    }
+
+    if template.len() > MAX_TEMPLATE {
+        bail!("A template can be at most {MAX_TEMPLATE} bytes long");
+    }
+
+    Ok(())
+}
+
+pub fn output(
+    result: Result<Evaluation>,
+    options: &EvalOptions,
+    settings: &GuildSettings,
+    elapsed: Duration,
+    footer: &str,
+) -> String {
+    output_after("", result, options, settings, elapsed, footer)
+}
+
+/// Like [`output`], with `prefix` put in front of the whole reply and taken out of its budget.
+pub fn output_after(
+    prefix: &str,
+    result: Result<Evaluation>,
+    options: &EvalOptions,
+    settings: &GuildSettings,
+    elapsed: Duration,
+    footer: &str,
+) -> String {
+    let (template, status, text, footer, lang) = match result {
+        Ok(eval) => {
+            let summary = match &eval.stats {
+                Some(summary) => stats(summary),
+                None if eval.exit != 0 => format!("\nexit status: {}", eval.exit),
+                None => String::new(),
+            };
+            let summary = if eval.verified {
+                format!("{summary}\ndeterministic: both runs printed the same")
+            } else {
+                summary
+            };
+            let summary = if eval.safe {
+                format!("{summary}\n(safe mode: no I/O)")
+            } else {
+                summary
+            };
+            let text = cap_lines(&combined(&eval), settings.max_lines);
+
+            let (text, lang) = match options.color {
+                Color::Strip => (ansi::strip(&text), ""),
+                Color::Ansi => (ansi::sanitize(&text), "ansi"),
+            };
+            (
+                settings.templates.result(&settings.locale),
+                "ok",
+                text,
+                format!("{summary}{footer}"),
+                lang,
+            )
+        }
+        Err(err) => (
+            settings.templates.error(&settings.locale),
+            "error",
+            error(&err, options.verbose, &settings.locale),
+            footer.to_string(),
+            "",
+        ),
+    };
+
+    let template = template
+        .replace("{status}", status)
+        .replace("{time}", &format!("{elapsed:.2?}"));
+    // The template is taken out of the budget first, the output is what gets truncated
+    let (before, after) = template.split_once("{output}").unwrap_or((&template, ""));
+    wrap(&format!("{prefix}{before}"), lang, &text, after, &footer)
+}
+
+/// The program's stdout, followed by its stderr when it wrote any.
+pub fn combined(eval: &Evaluation) -> String {
+    if eval.stderr.is_empty() {
+        eval.output.clone()
+    } else {
+        format!("{}\n── stderr ──\n{}", eval.output, eval.stderr)
+    }
+}
+
+/// Compares two outputs line by line, showing only the first few differences.
+pub fn diff(before: &str, after: &str) -> String {
+    let (before, after): (Vec<_>, Vec<_>) = (before.lines().collect(), after.lines().collect());
+    let differing: Vec<_> = (0..before.len().max(after.len()))
+        .filter(|&i| before.get(i) != after.get(i))
+        .collect();
+
+    let mut text: Vec<_> = differing
+        .iter()
+        .take(MAX_DIFF_LINES)
+        .map(|&i| {
+            let line = |lines: &[&str]| lines.get(i).copied().unwrap_or("<no line>");
+            format!("line {}:\n- {}\n+ {}", i + 1, line(&before), line(&after))
+        })
+        .collect();
+
+    if differing.len() > MAX_DIFF_LINES {
+        text.push(format!(
+            "… {} more differing lines",
+            differing.len() - MAX_DIFF_LINES
+        ));
+    }
+    text.join("\n")
+}
+
+/// Keeps the first `max` lines, so that many short lines don't flood the channel.
+fn cap_lines(text: &str, max: usize) -> String {
+    let total = text.lines().count();
+    if total <= max {
+        return text.to_string();
+    }
+
+    let kept: Vec<_> = text.lines().take(max).collect();
+    format!("{}\n… ({} more lines)", kept.join("\n"), total - max)
+}
+
+/// Describes an error, explaining traps in plain words and, when `verbose`, listing the
+/// WASM frames that led to them.
+pub fn error(err: &Error, verbose: bool, locale: &str) -> String {
+    if let Some(timed_out) = err.downcast_ref::<TimedOut>() {
+        return partial_output(timed_out, locale);
+    }
+
+    // Wasmtime may have attached a backtrace on top, which is of no interest here
+    if err.downcast_ref::<Cancelled>().is_some() {
+        return i18n::text(locale, "cancelled").to_string();
+    }
+
+    let Some(trap) = err.downcast_ref::<Trap>() else {
+        return err.to_string();
+    };
+
+    // The trap is all the more useful next to what the program got to print
+    let mut text = match err.downcast_ref::<PartialOutput>() {
+        Some(PartialOutput(output)) => {
+            let output = String::from_utf8_lossy(output);
+            let end = split_point(&output, PARTIAL_OUTPUT);
+            let crashed = i18n::text(locale, "then_crashed");
+            let explanation = explain(trap, locale);
+            format!("{}\n{crashed} {explanation}", output[..end].trim_end())
+        }
+        None => format!(
+            "{} {}",
+            i18n::text(locale, "crashed"),
+            explain(trap, locale)
+        ),
+    };
+    let backtrace = err.downcast_ref::<WasmBacktrace>().filter(|_| verbose);
+
+    if let Some(backtrace) = backtrace {
+        let frames = backtrace.frames();
+        for (i, frame) in frames.iter().take(MAX_FRAMES).enumerate() {
+            let name = frame.func_name().unwrap_or("<unnamed>");
+            text += &format!("\n  #{i} func[{}] {name}", frame.func_index());
+        }
+
+        if frames.len() > MAX_FRAMES {
+            text += &format!("\n  … {} more frames", frames.len() - MAX_FRAMES);
+        }
+    }
+
+    text
+}
+
+fn partial_output(timed_out: &TimedOut, locale: &str) -> String {
+    let headline =
+        i18n::text(locale, "timed_out").replace("{time}", &format!("{:?}", timed_out.after));
+    let output = String::from_utf8_lossy(&timed_out.output);
+    if output.is_empty() {
+        return format!("{headline}{}", i18n::text(locale, "printed_nothing"));
+    }
+
+    let end = split_point(&output, PARTIAL_OUTPUT);
+    let shown = i18n::text(locale, "partial_output").replace("{bytes}", &end.to_string());
+    format!("{headline}{shown}\n{}", &output[..end])
+}
+
+fn explain(trap: &Trap, locale: &str) -> String {
+    let key = match trap {
+        Trap::StackOverflow => "trap_stack_overflow",
+        Trap::MemoryOutOfBounds => "trap_memory_out_of_bounds",
+        Trap::HeapMisaligned => "trap_heap_misaligned",
+        Trap::IntegerOverflow => "trap_integer_overflow",
+        Trap::IntegerDivisionByZero => "trap_division_by_zero",
+        Trap::BadConversionToInteger => "trap_bad_conversion",
+        Trap::UnreachableCodeReached => "trap_unreachable",
+        Trap::Interrupt => "trap_interrupt",
+        Trap::OutOfFuel => "trap_out_of_fuel",
+        other => return other.to_string(),
+    };
+
+    i18n::text(locale, key).to_string()
+}
+
+/// Link back to the evaluated message, omitted outside of guilds.
+pub fn permalink(guild: Option<GuildId>, channel: ChannelId, message: MessageId) -> String {
+    guild.map_or_else(String::new, |guild| {
+        format!("\nhttps://discord.com/channels/{guild}/{channel}/{message}")
+    })
+}
+
+/// A one line summary of the resources used by the evaluation.
+fn stats(stats: &Stats) -> String {
+    let run = &stats.run;
+    let random = stats
+        .seed
+        .map_or_else(|| "host entropy".to_string(), |seed| format!("seed {seed}"));
+    let phases = stats.phases.as_ref().map_or_else(String::new, |phases| {
+        let phases: Vec<_> = phases
+            .iter()
+            .map(|(phase, time)| format!("{phase} {time:.2?}"))
+            .collect();
+        format!("\ncompile: {}", phases.join(" · "))
+    });
+    let summary = format!(
+        "\nmodule {} B · wall {:.2?} · fuel {} · memory {} pages · output {} B · exit {}\
+         \nrandom: {random} · clock: host, not frozen · cache key {:016x}",
+        stats.module_size,
+        run.wall,
+        run.fuel,
+        run.peak_pages,
+        stats.output_size,
+        run.exit,
+        stats.key
+    );
+    summary + &phases
+}
+
+/// Wraps `text` in a code block under `header`, truncating it to fit in a single message.
+///
+/// Replies are always plain messages so that edits can update them in place, hence long
+/// output is cut rather than moved to an attachment.
+pub fn block(header: &str, text: &str) -> String {
+    framed(header, text, "")
+}
+
+/// Wraps `text` in a code block that fits in an embed field.
+pub fn field(lang: &str, text: &str) -> String {
+    let overhead = lang.len() + "```\n\n```".len();
+    let text = text.replace("```", "`\u{200b}``");
+    let text = truncate(&text, FIELD_LIMIT - overhead);
+    format!("```{lang}\n{text}\n```")
+}
+
+fn framed(header: &str, text: &str, footer: &str) -> String {
+    wrap(&format!("{header}\n"), "", text, "", footer)
+}
+
+fn wrap(before: &str, lang: &str, text: &str, after: &str, footer: &str) -> String {
+    let overhead = before.len() + lang.len() + "```\n\n```".len() + after.len() + footer.len();
+    // A fence inside the output would close the block early
+    let text = text.replace("```", "`\u{200b}``");
+    let text = truncate(&text, MESSAGE_LIMIT.saturating_sub(overhead));
+    format!("{before}```{lang}\n{text}\n```{after}{footer}")
+}
+
+fn truncate(text: &str, limit: usize) -> Cow<'_, str> {
+    if text.len() <= limit {
+        return Cow::Borrowed(text);
+    }
+
+    let end = split_point(text, limit.saturating_sub(TRUNCATED.len()));
+    Cow::Owned(format!("{}{TRUNCATED}", text[..end].trim_end_matches('\n')))
+}
+
+/// Finds where to cut `text` so that it fits in `limit` bytes, preferring the last line
+/// break within `LINE_TOLERANCE` of the limit and never splitting a character.
+fn split_point(text: &str, limit: usize) -> usize {
+    if text.len() <= limit {
+        return text.len();
+    }
+
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    match text[..end].rfind('\n') {
+        Some(newline) if end - newline <= LINE_TOLERANCE => newline + 1,
+        _ => end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        limits::{self, Limits},
+        runtime::{Pipes, Runtime, Sandbox},
+    };
+
+    /// Runs hand-written WAT that is expected to trap.
+    fn trap(wat: &str) -> Error {
+        let runtime = Runtime::new().unwrap();
+        let compiled = runtime.assemble(wat).unwrap();
+        let limits = Limits::for_command(limits::Command::Eval);
+        let result = runtime.run(
+            &compiled.module,
+            &Pipes::new(false),
+            &Sandbox::default(),
+            limits,
+        );
+        result.err().expect("the program traps")
+    }
+
+    #[test]
+    fn explains_a_crash_with_its_backtrace() {
+        let err = trap(
+            r#"(module
+                (func $crash unreachable)
+                (func (export "_start") call $crash))"#,
+        );
+
+        let headline = "The program crashed: reached code marked as unreachable";
+        assert_eq!(error(&err, false, "en"), headline);
+
+        let verbose = error(&err, true, "en");
+        let frames: Vec<_> = verbose.lines().skip(1).collect();
+        assert!(verbose.starts_with(headline), "{verbose}");
+        assert_eq!(frames, ["  #0 func[0] crash", "  #1 func[1] <unnamed>"]);
+    }
+
+    #[test]
+    fn shows_what_was_printed_before_a_crash() {
+        let err = trap(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 8) "partial\n")
+                (func (export "_start")
+                    (i32.store (i32.const 0) (i32.const 8))
+                    (i32.store (i32.const 4) (i32.const 8))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 16)))
+                    unreachable))"#,
+        );
+
+        assert_eq!(
+            error(&err, false, "en"),
+            "partial\n… then crashed: reached code marked as unreachable"
+        );
+    }
+
+    #[test]
+    fn rejects_long_templates() {
+        assert!(validate_template(&format!("{}{{output}}", "a".repeat(100))).is_ok());
+        assert!(validate_template(&format!("{}{{output}}", "a".repeat(MAX_TEMPLATE))).is_err());
+        assert!(validate_template("no placeholder").is_err());
+    }
+
+    #[test]
+    fn long_templates_still_fit_in_a_message() {
+        let mut settings = GuildSettings::default();
+        let filler = "a".repeat(MAX_TEMPLATE - "{time}\n{output}".len());
+        let template = format!("{filler}{{time}}\n{{output}}");
+        validate_template(&template).unwrap();
+        settings.templates.result = Some(template);
+
+        let eval = Evaluation {
+            output: "b".repeat(MESSAGE_LIMIT * 2),
+            stderr: String::new(),
+            exit: 0,
+            stats: None,
+            verified: false,
+            safe: false,
+        };
+        let footer = "\nhttps://discord.com/channels/1/2/3";
+        let reply = output(
+            Ok(eval),
+            &EvalOptions::default(),
+            &settings,
+            Duration::from_millis(1234),
+            footer,
+        );
+
+        assert!(reply.len() <= MESSAGE_LIMIT, "{} bytes", reply.len());
+        assert!(reply.starts_with(&filler));
+        assert!(reply.contains(TRUNCATED));
+        assert!(reply.ends_with(footer));
+    }
+
+    #[test]
+    fn leaves_room_for_a_prefix() {
+        let eval = Evaluation {
+            output: "b".repeat(MESSAGE_LIMIT * 2),
+            stderr: String::new(),
+            exit: 0,
+            stats: None,
+            verified: false,
+            safe: false,
+        };
+        let prefix = format!("```\n{}```\n", "a".repeat(500));
+        let reply = output_after(
+            &prefix,
+            Ok(eval),
+            &EvalOptions::default(),
+            &GuildSettings::default(),
+            Duration::from_millis(1),
+            "",
+        );
+
+        assert!(reply.len() <= MESSAGE_LIMIT, "{} bytes", reply.len());
+        assert!(reply.starts_with(&prefix));
+        assert!(reply.contains(TRUNCATED));
+    }
+
+    #[test]
+    fn marks_safe_runs_outside_of_the_output() {
+        let eval = Evaluation {
+            output: "42".to_string(),
+            stderr: String::new(),
+            exit: 0,
+            stats: None,
+            verified: false,
+            safe: true,
+        };
+        let reply = output(
+            Ok(eval),
+            &EvalOptions::default(),
+            &GuildSettings::default(),
+            Duration::from_millis(1),
+            "",
+        );
+
+        assert!(reply.contains("```\n42\n```"), "{reply}");
+        assert!(reply.ends_with("\n(safe mode: no I/O)"), "{reply}");
+    }
+
+    fn timed_out(output: &[u8]) -> Error {
+        TimedOut {
+            after: Duration::from_secs(5),
+            output: output.to_vec(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn shows_what_a_timed_out_program_printed() {
+        assert_eq!(
+            error(&timed_out(b"tick\ntick\n"), false, "en"),
+            "The program did not terminate within 5s; showing the first 10 bytes of output:\n\
+             tick\ntick\n"
+        );
+        assert_eq!(
+            error(&timed_out(b""), false, "en"),
+            "The program did not terminate within 5s, and printed nothing."
+        );
+        assert_eq!(
+            error(&timed_out(b""), false, "pt"),
+            "O programa não terminou em 5s, e não imprimiu nada."
+        );
+    }
+
+    #[test]
+    fn caps_the_number_of_lines() {
+        let text: Vec<_> = (1..=30).map(|i| i.to_string()).collect();
+        let capped = cap_lines(&text.join("\n"), 10);
+
+        assert_eq!(
+            capped,
+            format!("{}\n… (20 more lines)", text[..10].join("\n"))
+        );
+        assert_eq!(capped.lines().count(), 11);
+        assert_eq!(cap_lines("1\n2", 2), "1\n2");
+    }
+
+    #[test]
+    fn keeps_text_that_fits() {
+        assert_eq!(split_point("short", 10), 5);
+    }
+
+    #[test]
+    fn cuts_after_a_nearby_line_break() {
+        let text = format!("{}\n{}", "a".repeat(50), "b".repeat(100));
+        assert_eq!(split_point(&text, 120), 51);
+    }
+
+    #[test]
+    fn cuts_at_the_limit_without_a_line_break() {
+        assert_eq!(split_point(&"a".repeat(300), 100), 100);
+
+        // A line break further back than the tolerance would waste too much of the message
+        let text = format!("a\n{}", "b".repeat(300));
+        assert_eq!(split_point(&text, 250), 250);
+    }
+
+    #[test]
+    fn never_splits_a_character() {
+        assert_eq!(split_point("ééé", 3), 2);
+        assert_eq!(split_point("aé", 2), 1);
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+
+const API: &str = "https://api.github.com/gists";
+/// Largest file uploaded, the rest is cut.
+const MAX_FILE_SIZE: usize = 64 * 1024;
+
+/// Uploads a snippet and its output as a secret gist, returning the gist's URL.
+pub async fn upload(token: &str, source: &str, output: &str) -> Result<String> {
+    let body = json!({
+        "description": "Shared from Flint",
+        "public": false,
+        "files": {
+            "source.fire": { "content": cap(source) },
+            "output.txt": { "content": cap(output) },
+        },
+    });
+
+    let response = reqwest::Client::new()
+        .post(API)
+        .bearer_auth(token)
+        .header("User-Agent", "flint")
+        .header("Accept", "application/vnd.github+json")
+        .json(&body)
+        .send()
+        .await
+        .with_context(|| "Could not reach GitHub")?;
+
+    let status = response.status();
+    if !status.is_success() {
+        bail!("GitHub refused the gist ({status})");
+    }
+
+    let gist: Value = response
+        .json()
+        .await
+        .with_context(|| "GitHub sent an invalid response")?;
+
+    gist["html_url"]
+        .as_str()
+        .map(str::to_string)
+        .with_context(|| "GitHub did not return the gist URL")
+}
+
+/// GitHub rejects empty files, and huge ones aren't worth sharing.
+fn cap(text: &str) -> &str {
+    if text.is_empty() {
+        return "(empty)";
+    }
+
+    let mut end = text.len().min(MAX_FILE_SIZE);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
@@ -0,0 +1,128 @@
+//! Friendlier explanations for common compile errors, used by `?explain`.
+
+struct Hint {
+    /// Lowercase words the error has to contain, as whole words.
+    pattern: &'static str,
+    explanation: &'static str,
+    fix: &'static str,
+}
+
+const HINTS: &[Hint] = &[
+    Hint {
+        pattern: "unknown word",
+        explanation: "The compiler found a name it doesn't know: it is not a builtin, \
+            nor a procedure, constant or variable defined before its use.",
+        fix: "Check the spelling, and that the definition comes before the place it is used.",
+    },
+    Hint {
+        pattern: "not enough",
+        explanation: "An operation needs more values on the stack than there are at that \
+            point of the program.",
+        fix: "Push the missing operands first, or check that an earlier word doesn't \
+            consume them.",
+    },
+    Hint {
+        pattern: "unhandled data",
+        explanation: "A block or procedure left values on the stack that nothing uses.",
+        fix: "Drop the extra values, or declare them in the procedure signature.",
+    },
+    Hint {
+        pattern: "mismatch",
+        explanation: "The values on the stack don't have the types an operation or \
+            signature expects.",
+        fix: "Compare the stack at that point with the expected types, a cast or a \
+            reordering is often enough.",
+    },
+    Hint {
+        pattern: "missing",
+        explanation: "A construct was not finished, the compiler reached something it \
+            didn't expect while still inside it.",
+        fix: "Check that every block is closed with `end`, and that it is written in full.",
+    },
+    Hint {
+        pattern: "unexpected end",
+        explanation: "The snippet ended in the middle of a construct.",
+        fix: "Close every open block with `end`.",
+    },
+    Hint {
+        pattern: "main",
+        explanation: "Every program needs an entry point, a procedure named `main`.",
+        fix: "Add a `proc main` with your code in it.",
+    },
+    Hint {
+        pattern: "failed to parse a code block",
+        explanation: "The code has to be written inside a code block, between triple \
+            backticks.",
+        fix: "Wrap the snippet in ``` fences, on their own lines.",
+    },
+];
+
+/// Explains `error` using the first matching hint, or returns it as is.
+pub fn explain(error: &str) -> String {
+    match find(error) {
+        Some(hint) => format!(
+            "{error}\n\n{}\n\nPossible fix: {}",
+            hint.explanation, hint.fix
+        ),
+        None => error.to_string(),
+    }
+}
+
+fn find(error: &str) -> Option<&'static Hint> {
+    let lower = error.to_lowercase();
+    HINTS
+        .iter()
+        .find(|hint| contains_words(&lower, hint.pattern))
+}
+
+/// Whether `phrase` appears in `text` as whole words, so that `main` doesn't match `domain`.
+fn contains_words(text: &str, phrase: &str) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(phrase).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + phrase.len()..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(error: &str) -> Option<&'static str> {
+        find(error).map(|hint| hint.pattern)
+    }
+
+    #[test]
+    fn matches_each_hint() {
+        let errors = [
+            ("Unknown word `prnt`", "unknown word"),
+            ("Not enough values on the stack for `+`", "not enough"),
+            ("Unhandled data on the stack", "unhandled data"),
+            ("Type mismatch, expected [int] but found [bool]", "mismatch"),
+            ("Missing `end` to close the `if` block", "missing"),
+            ("Unexpected end of file", "unexpected end"),
+            ("No `main` procedure found", "main"),
+            (
+                "Failed to parse a code block from the message",
+                "failed to parse a code block",
+            ),
+        ];
+
+        for (error, expected) in errors {
+            assert_eq!(pattern(error), Some(expected), "{error}");
+        }
+    }
+
+    #[test]
+    fn ignores_patterns_inside_other_words() {
+        assert_eq!(pattern("`remaining` is not a procedure"), None);
+        assert_eq!(pattern("`domain` is not a procedure"), None);
+        assert_eq!(pattern("`dismissing` is not a procedure"), None);
+    }
+
+    #[test]
+    fn keeps_unmatched_errors_as_is() {
+        assert_eq!(explain("something else"), "something else");
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::{HashMap, VecDeque};
+
+use serenity::model::prelude::{ChannelId, GuildId, MessageId, UserId};
+
+use crate::format;
+
+/// Evaluations remembered per user.
+const PER_USER: usize = 10;
+const PREVIEW_LENGTH: usize = 40;
+
+pub struct Entry {
+    pub guild: Option<GuildId>,
+    pub channel: ChannelId,
+    pub message: MessageId,
+    pub preview: String,
+    pub ok: bool,
+}
+
+impl Entry {
+    /// Shortens the snippet to its first line, for listing.
+    pub fn preview(code: &str) -> String {
+        let line = code
+            .lines()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or("");
+        let mut preview: String = line.trim().chars().take(PREVIEW_LENGTH).collect();
+        if preview.len() < line.trim().len() {
+            preview.push('…');
+        }
+        preview
+    }
+
+    fn describe(&self) -> String {
+        let outcome = if self.ok { "✅" } else { "❌" };
+        let link = format::permalink(self.guild, self.channel, self.message);
+        let location = if link.is_empty() {
+            " (direct message)".to_string()
+        } else {
+            link.replacen('\n', " ", 1)
+        };
+        format!("{outcome} `{}`{location}", self.preview.replace('`', "'"))
+    }
+}
+
+/// The last few evaluations of each user, newest last.
+#[derive(Default)]
+pub struct History {
+    users: HashMap<UserId, VecDeque<Entry>>,
+}
+
+impl History {
+    pub fn record(&mut self, user: UserId, entry: Entry) {
+        let entries = self.users.entry(user).or_default();
+        if entries.len() == PER_USER {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Lists a user's evaluations, newest first, within a single message.
+    pub fn describe(&self, user: UserId) -> String {
+        let Some(entries) = self.users.get(&user).filter(|entries| !entries.is_empty()) else {
+            return "No recent evaluations.".to_string();
+        };
+
+        let mut text = String::new();
+        for entry in entries.iter().rev() {
+            let line = entry.describe();
+            if text.len() + line.len() + 1 > format::MESSAGE_LIMIT {
+                break;
+            }
+            text += &line;
+            text.push('\n');
+        }
+
+        text
+    }
+}
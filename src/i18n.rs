@@ -0,0 +1,261 @@
+//! Catalog of the fixed strings shown to users, by locale.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Each key with its translations. Adding a language only takes adding its entries here,
+/// strings missing from a locale fall back to English.
+const MESSAGES: &[(&str, &[(&str, &str)])] = &[
+    (
+        "result_header",
+        &[
+            ("en", "Compilation result:"),
+            ("pt", "Resultado da compilação:"),
+            ("es", "Resultado de la compilación:"),
+        ],
+    ),
+    (
+        "error_header",
+        &[
+            ("en", "Compilation error:"),
+            ("pt", "Erro de compilação:"),
+            ("es", "Error de compilación:"),
+        ],
+    ),
+    (
+        "crashed",
+        &[
+            ("en", "The program crashed:"),
+            ("pt", "O programa falhou:"),
+            ("es", "El programa falló:"),
+        ],
+    ),
+    (
+        "then_crashed",
+        &[
+            ("en", "… then crashed:"),
+            ("pt", "… e então falhou:"),
+            ("es", "… y luego falló:"),
+        ],
+    ),
+    (
+        "timed_out",
+        &[
+            ("en", "The program did not terminate within {time}"),
+            ("pt", "O programa não terminou em {time}"),
+            ("es", "El programa no terminó en {time}"),
+        ],
+    ),
+    (
+        "printed_nothing",
+        &[
+            ("en", ", and printed nothing."),
+            ("pt", ", e não imprimiu nada."),
+            ("es", ", y no imprimió nada."),
+        ],
+    ),
+    (
+        "partial_output",
+        &[
+            ("en", "; showing the first {bytes} bytes of output:"),
+            ("pt", "; mostrando os primeiros {bytes} bytes da saída:"),
+            ("es", "; mostrando los primeros {bytes} bytes de la salida:"),
+        ],
+    ),
+    (
+        "paused",
+        &[
+            ("en", "Evaluations are temporarily paused."),
+            ("pt", "As avaliações estão temporariamente pausadas."),
+            ("es", "Las evaluaciones están pausadas temporalmente."),
+        ],
+    ),
+    (
+        "stopping",
+        &[
+            ("en", "The bot is restarting, try again in a moment."),
+            (
+                "pt",
+                "O bot está reiniciando, tente novamente em instantes.",
+            ),
+            (
+                "es",
+                "El bot se está reiniciando, inténtalo de nuevo en un momento.",
+            ),
+        ],
+    ),
+    (
+        "attachment_error",
+        &[
+            ("en", "Attachment error:"),
+            ("pt", "Erro no anexo:"),
+            ("es", "Error en el adjunto:"),
+        ],
+    ),
+    (
+        "running",
+        &[
+            ("en", "Running… (react with {emoji} to cancel)"),
+            ("pt", "Executando… (reaja com {emoji} para cancelar)"),
+            ("es", "Ejecutando… (reacciona con {emoji} para cancelar)"),
+        ],
+    ),
+    (
+        "cancelled",
+        &[
+            ("en", "cancelled by user"),
+            ("pt", "cancelado pelo usuário"),
+            ("es", "cancelado por el usuario"),
+        ],
+    ),
+    (
+        "selftest_passed",
+        &[
+            ("en", "Self-test passed:"),
+            ("pt", "Autoteste aprovado:"),
+            ("es", "Autoprueba superada:"),
+        ],
+    ),
+    (
+        "selftest_failed",
+        &[
+            ("en", "Self-test failed:"),
+            ("pt", "Autoteste falhou:"),
+            ("es", "Autoprueba fallida:"),
+        ],
+    ),
+    (
+        "trap_stack_overflow",
+        &[
+            ("en", "stack overflow, likely caused by unbounded recursion"),
+            (
+                "pt",
+                "estouro de pilha, provavelmente causado por recursão sem fim",
+            ),
+            (
+                "es",
+                "desbordamiento de pila, probablemente causado por una recursión sin fin",
+            ),
+        ],
+    ),
+    (
+        "trap_memory_out_of_bounds",
+        &[
+            ("en", "tried to access memory outside of its bounds"),
+            ("pt", "tentou acessar memória fora dos seus limites"),
+            ("es", "intentó acceder a memoria fuera de sus límites"),
+        ],
+    ),
+    (
+        "trap_heap_misaligned",
+        &[
+            ("en", "tried to access misaligned memory"),
+            ("pt", "tentou acessar memória desalinhada"),
+            ("es", "intentó acceder a memoria desalineada"),
+        ],
+    ),
+    (
+        "trap_integer_overflow",
+        &[
+            ("en", "integer overflow"),
+            ("pt", "estouro de inteiro"),
+            ("es", "desbordamiento de entero"),
+        ],
+    ),
+    (
+        "trap_division_by_zero",
+        &[
+            ("en", "division by zero"),
+            ("pt", "divisão por zero"),
+            ("es", "división por cero"),
+        ],
+    ),
+    (
+        "trap_bad_conversion",
+        &[
+            ("en", "invalid conversion to an integer"),
+            ("pt", "conversão inválida para inteiro"),
+            ("es", "conversión inválida a entero"),
+        ],
+    ),
+    (
+        "trap_unreachable",
+        &[
+            ("en", "reached code marked as unreachable"),
+            ("pt", "alcançou código marcado como inalcançável"),
+            ("es", "alcanzó código marcado como inalcanzable"),
+        ],
+    ),
+    (
+        "trap_interrupt",
+        &[
+            ("en", "it ran for too long and was stopped"),
+            ("pt", "executou por tempo demais e foi interrompido"),
+            ("es", "se ejecutó durante demasiado tiempo y fue detenido"),
+        ],
+    ),
+    (
+        "trap_out_of_fuel",
+        &[
+            ("en", "ran out of fuel"),
+            ("pt", "ficou sem combustível"),
+            ("es", "se quedó sin combustible"),
+        ],
+    ),
+];
+
+type Catalog = HashMap<&'static str, HashMap<&'static str, &'static str>>;
+
+fn catalog() -> &'static Catalog {
+    static CATALOG: OnceLock<Catalog> = OnceLock::new();
+    CATALOG.get_or_init(|| {
+        MESSAGES
+            .iter()
+            .map(|(key, translations)| (*key, translations.iter().copied().collect()))
+            .collect()
+    })
+}
+
+/// The text of `key` in `locale`, or in English when it has no translation.
+pub fn text(locale: &str, key: &'static str) -> &'static str {
+    let Some(translations) = catalog().get(key) else {
+        return key;
+    };
+
+    translations
+        .get(locale)
+        .or_else(|| translations.get(DEFAULT_LOCALE))
+        .copied()
+        .unwrap_or(key)
+}
+
+/// Whether any message is translated to `locale`.
+pub fn is_known(locale: &str) -> bool {
+    catalog()
+        .values()
+        .any(|translations| translations.contains_key(locale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_every_message() {
+        for (key, translations) in MESSAGES {
+            for locale in ["en", "pt", "es"] {
+                assert!(
+                    translations.iter().any(|(known, _)| *known == locale),
+                    "`{key}` has no {locale} translation"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_english() {
+        assert_eq!(text("fr", "cancelled"), "cancelled by user");
+        assert_eq!(text("pt", "cancelled"), "cancelado pelo usuário");
+    }
+}
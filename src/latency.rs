@@ -0,0 +1,154 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds of the buckets in milliseconds, the last one takes everything slower.
+const BOUNDS: [u64; 14] = [
+    1,
+    2,
+    5,
+    10,
+    20,
+    50,
+    100,
+    200,
+    500,
+    1_000,
+    2_000,
+    5_000,
+    10_000,
+    u64::MAX,
+];
+const PERCENTILES: [u64; 3] = [50, 95, 99];
+
+/// Counts samples in fixed buckets, so it takes the same memory however many are recorded.
+#[derive(Default)]
+pub struct Histogram {
+    counts: [AtomicU64; BOUNDS.len()],
+}
+
+impl Histogram {
+    pub fn record(&self, elapsed: Duration) {
+        let millis = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        let bucket = BOUNDS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(BOUNDS.len() - 1);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn describe(&self) -> String {
+        let counts: Vec<_> = self
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return "no samples".to_string();
+        }
+
+        let percentiles = PERCENTILES.map(|p| {
+            let bound = percentile(&counts, total, p);
+            format!("p{p} {}", show_bound(bound))
+        });
+        format!("{} ({total} samples)", percentiles.join("  "))
+    }
+}
+
+/// The bound of the bucket holding the `p`th percentile sample.
+fn percentile(counts: &[u64], total: u64, p: u64) -> u64 {
+    let rank = (total * p + 99) / 100;
+    let mut seen = 0;
+    for (count, bound) in counts.iter().zip(BOUNDS) {
+        seen += count;
+        if seen >= rank {
+            return bound;
+        }
+    }
+
+    u64::MAX
+}
+
+fn show_bound(bound: u64) -> String {
+    if bound == u64::MAX {
+        format!(">{}ms", BOUNDS[BOUNDS.len() - 2])
+    } else {
+        format!("≤{bound}ms")
+    }
+}
+
+/// How long evaluations take, with compiling and running kept apart.
+#[derive(Default)]
+pub struct Latency {
+    pub compile: Histogram,
+    pub run: Histogram,
+    pub total: Histogram,
+}
+
+impl Latency {
+    pub fn describe(&self) -> String {
+        [
+            format!("compile {}", self.compile.describe()),
+            format!("run     {}", self.run.describe()),
+            format!("total   {}", self.total.describe()),
+        ]
+        .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(samples: &[(u64, usize)]) -> Histogram {
+        let histogram = Histogram::default();
+        for &(millis, count) in samples {
+            for _ in 0..count {
+                histogram.record(Duration::from_millis(millis));
+            }
+        }
+        histogram
+    }
+
+    #[test]
+    fn describes_percentiles_by_bucket() {
+        let histogram = recorded(&[(10, 90), (100, 10)]);
+        assert_eq!(
+            histogram.describe(),
+            "p50 ≤10ms  p95 ≤100ms  p99 ≤100ms (100 samples)"
+        );
+    }
+
+    #[test]
+    fn rounds_ranks_up() {
+        // The 95th of 100 samples is the first of the slower ones
+        let histogram = recorded(&[(10, 94), (100, 6)]);
+        assert!(histogram.describe().contains("p95 ≤100ms"));
+
+        let histogram = recorded(&[(10, 95), (100, 5)]);
+        assert!(histogram.describe().contains("p95 ≤10ms"));
+    }
+
+    #[test]
+    fn puts_samples_in_the_first_bucket_they_fit() {
+        let histogram = recorded(&[(0, 1), (1, 1), (3, 1), (10_000, 1)]);
+        let counts: Vec<_> = histogram
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(counts, [2, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn shows_the_overflow_bucket() {
+        let histogram = recorded(&[(1, 1), (60_000, 1)]);
+        assert_eq!(
+            histogram.describe(),
+            "p50 ≤1ms  p95 >10000ms  p99 >10000ms (2 samples)"
+        );
+        assert_eq!(Histogram::default().describe(), "no samples");
+    }
+}
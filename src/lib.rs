@@ -1,57 +1,402 @@
+mod abuse;
+mod ansi;
+mod attachment;
+mod cache;
+mod content;
+mod dedup;
+mod examples;
+mod format;
+mod gist;
+mod hints;
+mod history;
+mod i18n;
+mod latency;
+mod limits;
+mod options;
+mod parser;
+mod runtime;
+mod settings;
+mod shutdown;
+mod tracking;
+
 use std::{
-    collections::HashMap,
-    io::{BufReader, BufWriter},
-    sync::Arc,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant},
 };
 
+use abuse::Abuse;
 use anyhow::{anyhow, Context as AnyCtx, Result};
-use ashfire::target::Target;
+use cache::Compiled;
+use dedup::Recent;
+use history::History;
+use limits::Limits;
+use options::EvalOptions;
+use runtime::{CompileTimeout, Pipes, RunStats, Runtime, Sandbox, TargetKind};
 use serenity::{
     async_trait,
     framework::standard::{
         macros::{command, group, hook},
         Args, CommandResult, StandardFramework,
     },
+    http::Http,
     model::prelude::*,
     prelude::*,
 };
+use settings::{EditMode, GuildSettings, Settings};
+use shutdown::{InFlight, InFlightGuard};
 use shuttle_secrets::SecretStore;
-use tracing::{error, info};
-use wasmtime::*;
-use wasmtime_wasi::sync::WasiCtxBuilder;
+use tracing::{error, info, warn};
+use tracking::{Tracked, Tracker};
+use wasmtime::Module;
 
 struct BotData;
 
 impl TypeMapKey for BotData {
-    type Value = Arc<RwLock<HashMap<MessageId, MessageId>>>;
+    type Value = Arc<RwLock<Tracker>>;
+}
+
+struct GuildConfig;
+
+impl TypeMapKey for GuildConfig {
+    type Value = Arc<RwLock<Settings>>;
+}
+
+struct Owners;
+
+impl TypeMapKey for Owners {
+    type Value = Arc<HashSet<UserId>>;
+}
+
+struct Running;
+
+impl TypeMapKey for Running {
+    type Value = Arc<InFlight>;
+}
+
+struct Seen;
+
+impl TypeMapKey for Seen {
+    type Value = Arc<Mutex<Recent>>;
+}
+
+struct Evaluations;
+
+impl TypeMapKey for Evaluations {
+    type Value = Arc<RwLock<History>>;
+}
+
+/// Streamed evaluations that can still be cancelled, by their running reply, with the author
+/// allowed to do it.
+///
+/// The lock is never held across an await, so that a [`CancelGuard`] can take it on drop.
+struct Cancellable;
+
+impl TypeMapKey for Cancellable {
+    type Value = Arc<StdMutex<HashMap<MessageId, (UserId, Arc<AtomicBool>)>>>;
+}
+
+struct Offenders;
+
+impl TypeMapKey for Offenders {
+    type Value = Arc<Mutex<Abuse>>;
+}
+
+struct GistToken;
+
+impl TypeMapKey for GistToken {
+    type Value = Option<String>;
+}
+
+struct Paused;
+
+impl TypeMapKey for Paused {
+    type Value = Arc<AtomicBool>;
 }
 
-struct Bot;
+const PREFIX: &str = "?";
+const PIN_EMOJI: &str = "📌";
+const CANCEL_EMOJI: &str = "❌";
+
+async fn tracker(ctx: &Context) -> Arc<RwLock<Tracker>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<BotData>()
+        .expect("Expected BotData in TypeMap.")
+        .clone()
+}
+
+async fn cancellable(
+    ctx: &Context,
+) -> Arc<StdMutex<HashMap<MessageId, (UserId, Arc<AtomicBool>)>>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<Cancellable>()
+        .expect("Expected Cancellable in TypeMap.")
+        .clone()
+}
+
+async fn offenders(ctx: &Context) -> Arc<Mutex<Abuse>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<Offenders>()
+        .expect("Expected Offenders in TypeMap.")
+        .clone()
+}
+
+/// Whether a user is muted for exhausting the shared resources, which bot owners never are.
+async fn muted(ctx: &Context, user: UserId) -> bool {
+    !is_owner(ctx, user).await && offenders(ctx).await.lock().await.is_muted(user)
+}
+
+/// Counts the runs that exhausted a limit, muting the user once there are too many.
+async fn record_outcome(ctx: &Context, user: UserId, outcome: Outcome) {
+    if outcome == Outcome::ResourceLimit && offenders(ctx).await.lock().await.hit(user) {
+        warn!(
+            "Muting {} after repeatedly hitting the resource limits",
+            user
+        );
+    }
+}
+
+async fn paused(ctx: &Context) -> bool {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<Paused>()
+        .expect("Expected Paused in TypeMap.")
+        .load(Ordering::Relaxed)
+}
+
+async fn settings(ctx: &Context, guild: Option<GuildId>) -> GuildSettings {
+    let data_read = ctx.data.read().await;
+    let data_lock = data_read
+        .get::<GuildConfig>()
+        .expect("Expected GuildConfig in TypeMap.");
+    let settings = data_lock.read().await;
+    settings.get(guild)
+}
+
+async fn footer(
+    ctx: &Context,
+    guild: Option<GuildId>,
+    channel: ChannelId,
+    message: MessageId,
+) -> String {
+    let settings = settings(ctx, guild).await;
+    let mut footer = if settings.permalink {
+        format::permalink(guild, channel, message)
+    } else {
+        String::new()
+    };
+
+    if settings.show_compiler {
+        footer += &format!("\ncompiled with ashfire {}", runtime::COMPILER);
+    }
+    footer
+}
+
+/// Whether this revision of a message hasn't been handled yet.
+async fn first_delivery(ctx: &Context, id: MessageId, edited: Option<Timestamp>) -> bool {
+    let data_read = ctx.data.read().await;
+    let recent = data_read.get::<Seen>().expect("Expected Seen in TypeMap.");
+    let first = recent.lock().await.first_time(id, edited);
+    first
+}
+
+async fn is_owner(ctx: &Context, user: UserId) -> bool {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<Owners>()
+        .expect("Expected Owners in TypeMap.")
+        .contains(&user)
+}
+
+/// Whether the author may change the settings of the guild the message was sent in: bot
+/// owners, the guild owner and members allowed to manage it.
+async fn can_configure(ctx: &Context, msg: &Message) -> bool {
+    if is_owner(ctx, msg.author.id).await {
+        return true;
+    }
+
+    let (Some(guild), Some(member)) = (msg.guild_id, &msg.member) else {
+        return false;
+    };
+    let guild = match guild.to_partial_guild(ctx).await {
+        Ok(guild) => guild,
+        Err(why) => {
+            error!("Could not fetch guild {}: {:?}", guild, why);
+            return false;
+        }
+    };
+
+    if guild.owner_id == msg.author.id {
+        return true;
+    }
+
+    // Without the cache the framework can't check permissions, so they are computed from the
+    // member's roles, the @everyone role sharing the guild's id
+    let everyone = RoleId(guild.id.0);
+    let permissions = member
+        .roles
+        .iter()
+        .chain([&everyone])
+        .filter_map(|role| guild.roles.get(role))
+        .fold(Permissions::empty(), |all, role| all | role.permissions);
+
+    permissions.administrator() || permissions.manage_guild()
+}
+
+/// Registers a new evaluation, or returns why none can be started right now.
+async fn begin_evaluation(
+    ctx: &Context,
+    guild: Option<GuildId>,
+) -> Result<InFlightGuard, &'static str> {
+    let locale = settings(ctx, guild).await.locale;
+    if paused(ctx).await {
+        return Err(i18n::text(&locale, "paused"));
+    }
+
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<Running>()
+        .expect("Expected Running in TypeMap.")
+        .enter()
+        .ok_or_else(|| i18n::text(&locale, "stopping"))
+}
+
+async fn set_paused(ctx: &Context, value: bool) {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<Paused>()
+        .expect("Expected Paused in TypeMap.")
+        .store(value, Ordering::Relaxed);
+}
+
+struct Bot {
+    ops_channel: Option<ChannelId>,
+    checked: AtomicBool,
+}
 
 #[async_trait]
 impl EventHandler for Bot {
     async fn message_update(&self, ctx: Context, msg: MessageUpdateEvent) {
-        let lock = {
-            let data_read = ctx.data.read().await;
-            let data_lock = data_read
-                .get::<BotData>()
-                .expect("Expected BotData in TypeMap.");
-            let hashmap = data_lock.read().await;
-            hashmap.get(&msg.id).copied()
-        };
+        // Replies are edited by the bot itself, which must never trigger an evaluation
+        if matches!(&msg.author, Some(author) if author.bot) {
+            return;
+        }
+
+        let lock = tracker(&ctx).await;
+        let reply = lock.read().await.get(&msg.id).map(|entry| entry.reply);
 
-        if let Some(id) = lock {
+        if let Some(id) = reply {
             if let Err(why) = edit(&ctx, msg, id).await {
                 error!("Error in edit: {:?}", why);
             }
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        _: ChannelId,
+        deleted_message_id: MessageId,
+        _: Option<GuildId>,
+    ) {
+        let lock = tracker(&ctx).await;
+        let mut tracker = lock.write().await;
+        tracker.remove(&deleted_message_id);
+
+        // A deleted reply can't be edited anymore either
+        if let Some(source) = tracker.source_of(deleted_message_id) {
+            tracker.remove(&source);
+        }
+    }
+
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        let ReactionType::Unicode(emoji) = &reaction.emoji else {
+            return;
+        };
+
+        match emoji.as_str() {
+            PIN_EMOJI => pin_reply(&ctx, &reaction).await,
+            CANCEL_EMOJI => cancel_run(&ctx, &reaction).await,
+            _ => {}
+        }
+    }
+
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
+
+        if self.checked.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        // The first run warms the shared runtime up, so later evaluations start fast
+        let start = Instant::now();
+        let result = run_selftest().await;
+        info!("Warm-up and self-test took {:?}", start.elapsed());
+
+        let status = match result {
+            Ok(_) => {
+                info!("Self-test passed");
+                "Self-test passed, ready to evaluate.".to_string()
+            }
+            Err(why) => {
+                error!("SELF-TEST FAILED, evaluations will not work: {:?}", why);
+                format::block("Self-test failed:", &why.to_string())
+            }
+        };
+
+        if let Some(channel) = self.ops_channel {
+            if let Err(why) = channel.say(&ctx, status).await {
+                error!("Error posting self-test status: {:?}", why);
+            }
+        }
+    }
+}
+
+/// Keeps a reply its author reacted on from expiring.
+async fn pin_reply(ctx: &Context, reaction: &Reaction) {
+    let lock = tracker(ctx).await;
+    let mut tracker = lock.write().await;
+    if let Some(entry) = tracker.by_reply_mut(reaction.message_id) {
+        if reaction.user_id == Some(entry.author) {
+            info!("Pinning reply: {}", entry.reply);
+            entry.pinned = true;
+        }
+    }
+}
+
+/// Stops a streamed evaluation whose author reacted on its running reply.
+async fn cancel_run(ctx: &Context, reaction: &Reaction) {
+    let lock = cancellable(ctx).await;
+    let cancellable = lock.lock().expect("Cancellable lock poisoned");
+    if let Some((author, cancel)) = cancellable.get(&reaction.message_id) {
+        if reaction.user_id == Some(*author) {
+            info!("Cancelling evaluation: {}", reaction.message_id);
+            cancel.store(true, Ordering::Relaxed);
+        }
     }
 }
 
+/// The guild's own prefix, or the default one.
+#[hook]
+async fn guild_prefix(ctx: &Context, msg: &Message) -> Option<String> {
+    let prefix = settings(ctx, msg.guild_id).await.prefix;
+    Some(prefix.unwrap_or_else(|| PREFIX.to_string()))
+}
+
+/// Ignores users muted for exhausting the shared resources, admins excepted.
+#[hook]
+async fn before_hook(ctx: &Context, msg: &Message, _: &str) -> bool {
+    !muted(ctx, msg.author.id).await
+}
+
 #[hook]
 async fn after_hook(_: &Context, _: &Message, cmd_name: &str, error: CommandResult) {
     //  Print out an error if it happened
@@ -69,113 +414,1287 @@ async fn serenity(
         return Err(anyhow!("'DISCORD_TOKEN' was not found").into());
     };
 
+    // Optional channel where startup status is reported
+    let ops_channel = secret_store
+        .get("OPS_CHANNEL")
+        .and_then(|id| id.parse().ok())
+        .map(ChannelId);
+
+    // Optional GitHub token used to share snippets as gists
+    let gist_token = secret_store.get("GITHUB_TOKEN");
+
+    // The runtime is only set up once, so a broken setup stops the bot right away rather
+    // than failing every evaluation
+    Runtime::get().context("Could not set up the WebAssembly runtime")?;
+
+    // Bot owners are allowed to use the admin commands
+    let http = Http::new(&token);
+    let info = http
+        .get_current_application_info()
+        .await
+        .context("Could not access application info")?;
+
+    // Mentioning the bot works as an alternative to the text prefix
+    let bot_id = http
+        .get_current_user()
+        .await
+        .context("Could not access the bot user")?
+        .id;
+
+    let mut owners = HashSet::new();
+    match info.team {
+        Some(team) => owners.insert(team.owner_user_id),
+        None => owners.insert(info.owner.id),
+    };
+
     // Set gateway intents, which decides what events the bot will be notified about
-    let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    let intents = GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::GUILD_MESSAGE_REACTIONS
+        | GatewayIntents::MESSAGE_CONTENT;
 
     let framework = StandardFramework::new()
-        .configure(|c| c.with_whitespace(false).prefix("?"))
+        .configure(|c| {
+            c.with_whitespace(false)
+                // An empty static prefix leaves only the guild's, so that a custom one
+                // replaces the default instead of adding to it
+                .prefix("")
+                .dynamic_prefix(guild_prefix)
+                .on_mention(Some(bot_id))
+                .ignore_bots(true)
+                .ignore_webhooks(true)
+                .owners(owners.clone())
+        })
+        // Gists go through an external API, so their use is rate limited
+        .bucket("gist", |b| b.delay(30).time_span(60 * 60).limit(10))
+        .await
+        .bucket("rerun", |b| b.delay(5))
+        .await
         .group(&DEFAULT_GROUP)
+        .before(before_hook)
         .after(after_hook);
 
+    let in_flight = Arc::new(InFlight::default());
+    let tracker = Arc::new(RwLock::new(Tracker::default()));
     let client = Client::builder(&token, intents)
-        .event_handler(Bot)
+        .event_handler(Bot {
+            ops_channel,
+            checked: AtomicBool::new(false),
+        })
         .framework(framework)
-        .type_map_insert::<BotData>(Arc::new(RwLock::new(HashMap::default())))
+        .type_map_insert::<BotData>(tracker.clone())
+        .type_map_insert::<GuildConfig>(Arc::new(RwLock::new(Settings::default())))
+        .type_map_insert::<Owners>(Arc::new(owners))
+        .type_map_insert::<Evaluations>(Arc::new(RwLock::new(History::default())))
+        .type_map_insert::<GistToken>(gist_token)
+        .type_map_insert::<Seen>(Arc::new(Mutex::new(Recent::default())))
+        .type_map_insert::<Cancellable>(Arc::default())
+        .type_map_insert::<Offenders>(Arc::default())
+        .type_map_insert::<Paused>(Arc::new(AtomicBool::new(false)))
+        .type_map_insert::<Running>(in_flight.clone())
         .await
-        .expect("Err creating client");
+        .context("Could not create the Discord client")?;
+
+    tokio::spawn(tracking::sweep(tracker));
+    tokio::spawn(shutdown::on_signal(in_flight, client.shard_manager.clone()));
 
     Ok(client)
 }
 
 #[group("default")]
-#[commands(eval)]
+#[commands(
+    eval, wateval, example, exports, artifacts, explain, gist, rerun, pin, history, selftest,
+    targets, cache, cachestats, latency, whoami, limits, env, config, pause, resume, unmute
+)]
 struct Default;
 
 #[command]
 async fn eval(ctx: &Context, msg: &Message, mut _args: Args) -> CommandResult {
+    if !first_delivery(ctx, msg.id, msg.edited_timestamp).await {
+        return Ok(());
+    }
+
+    let _guard = match begin_evaluation(ctx, msg.guild_id).await {
+        Ok(guard) => guard,
+        Err(why) => {
+            msg.reply(ctx, why).await?;
+            return Ok(());
+        }
+    };
+
     info!("Evaluating message: {}", msg.id);
 
-    let output = compile_otput(&msg.content, &msg.author.name);
-    let reply = msg.reply(ctx, output).await?;
+    let referenced = msg.referenced_message.as_deref();
+    let content = match resolve_source(&msg.content, referenced, &msg.attachments).await {
+        Ok(content) => content,
+        Err(err) => {
+            let locale = settings(ctx, msg.guild_id).await.locale;
+            let header = i18n::text(&locale, "attachment_error");
+            msg.reply(ctx, format::block(header, &err.to_string()))
+                .await?;
+            return Ok(());
+        }
+    };
+    if let Ok((options, code)) = parse(&content) {
+        if options.stream {
+            let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+            return stream(ctx, msg, &content, code, &options, settings).await;
+        }
+    }
+
+    let footer = footer(ctx, msg.guild_id, msg.channel_id, msg.id).await;
+    let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+    let (output, outcome) = compile_otput(&content, &msg.author.name, &settings, &footer).await;
+    let reply = msg.reply(ctx, output).await;
+
+    track(ctx, msg, reply, &content, outcome == Outcome::Ok).await?;
+    record_outcome(ctx, msg.author.id, outcome).await;
+    Ok(())
+}
+
+/// Runs hand-written WAT, which sidesteps the compiler and so is kept to the bot owners.
+#[command]
+#[owners_only]
+async fn wateval(ctx: &Context, msg: &Message) -> CommandResult {
+    let _guard = match begin_evaluation(ctx, msg.guild_id).await {
+        Ok(guard) => guard,
+        Err(why) => {
+            msg.reply(ctx, why).await?;
+            return Ok(());
+        }
+    };
+
+    info!("Evaluating WAT from {}: {}", msg.author.tag(), msg.id);
+
+    let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+    let start = Instant::now();
+    let (options, result) = match parse(&msg.content) {
+        Ok((options, wat)) => {
+            let result = evaluate_wat(&wat, &options, &settings.sandbox).await;
+            (options, result)
+        }
+        Err(err) => (EvalOptions::default(), Err(err)),
+    };
+
+    let footer = footer(ctx, msg.guild_id, msg.channel_id, msg.id).await;
+    let output = format::output(result, &options, &settings, start.elapsed(), &footer);
+    msg.reply(ctx, output).await?;
+    Ok(())
+}
+
+/// Runs the snippet in the background, periodically editing a placeholder reply with the
+/// output produced so far, then with the final result.
+async fn stream(
+    ctx: &Context,
+    msg: &Message,
+    content: &str,
+    code: String,
+    options: &EvalOptions,
+    settings: GuildSettings,
+) -> CommandResult {
+    let running = i18n::text(&settings.locale, "running").replace("{emoji}", CANCEL_EMOJI);
+    let mut reply = msg.reply(ctx, &running).await?;
+
+    let start = Instant::now();
+    let pipes = Pipes::new(options.merge_streams);
+    let sandbox = settings.sandbox.with_seed(options.seed);
+    let limits = Limits::for_command(limits::Command::Eval);
+    let mut task = {
+        let name = options.module_name(&msg.author.name);
+        let (pipes, sandbox) = (pipes.clone(), sandbox.clone());
+        tokio::spawn(async move { execute(&code, &name, &pipes, &sandbox, limits).await })
+    };
+
+    let guard = CancelGuard::register(ctx, reply.id, msg.author.id, &pipes).await;
+
+    let mut shown = Vec::new();
+    let joined = loop {
+        tokio::select! {
+            joined = &mut task => break joined,
+            _ = tokio::time::sleep(settings.stream_interval) => {
+                let snapshot = pipes.stdout.contents();
+                if snapshot != shown {
+                    // The next edit or the final one may well go through, so the run goes on
+                    let partial = format::block(&running, &String::from_utf8_lossy(&snapshot));
+                    match reply.edit(ctx, |m| m.content(partial)).await {
+                        Ok(()) => shown = snapshot,
+                        Err(why) => warn!("Error showing partial output: {:?}", why),
+                    }
+                }
+            }
+        }
+    };
+
+    // Once finished there is nothing left to cancel
+    drop(guard);
+
+    // The second run of `--verify-deterministic` isn't streamed, only its difference shown
+    let result = match joined {
+        Ok(Ok((compiled, run))) => {
+            conclude(&compiled, run, &pipes, options, &sandbox, limits).await
+        }
+        Ok(Err(err)) => Err(err),
+        Err(err) => Err(err.into()),
+    };
+
+    let footer = footer(ctx, msg.guild_id, msg.channel_id, msg.id).await;
+    let outcome = Outcome::of(&result);
+    let output = format::output(result, options, &settings, start.elapsed(), &footer);
+    let edited = reply.edit(ctx, |m| m.content(output)).await.map(|()| reply);
+
+    track(ctx, msg, edited, content, outcome == Outcome::Ok).await?;
+    record_outcome(ctx, msg.author.id, outcome).await;
+    Ok(())
+}
+
+/// Keeps a streamed run cancellable until dropped, then stops it if it is still going, so that
+/// no exit path leaves it running until its timeout or its entry behind.
+struct CancelGuard {
+    cancellable: Arc<StdMutex<HashMap<MessageId, (UserId, Arc<AtomicBool>)>>>,
+    reply: MessageId,
+    cancel: Arc<AtomicBool>,
+}
+
+impl CancelGuard {
+    async fn register(ctx: &Context, reply: MessageId, author: UserId, pipes: &Pipes) -> Self {
+        let cancellable = cancellable(ctx).await;
+        let cancel = pipes.cancel.clone();
+        cancellable
+            .lock()
+            .expect("Cancellable lock poisoned")
+            .insert(reply, (author, cancel.clone()));
+
+        Self {
+            cancellable,
+            reply,
+            cancel,
+        }
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        self.cancellable
+            .lock()
+            .expect("Cancellable lock poisoned")
+            .remove(&self.reply);
+    }
+}
 
-    {
+/// Records an evaluation once its reply is posted in its final form, so that neither the
+/// tracker nor the history point at a reply that failed to be sent.
+///
+/// `content` is the text that was evaluated, which the code preview is taken from.
+async fn track(
+    ctx: &Context,
+    msg: &Message,
+    reply: serenity::Result<Message>,
+    content: &str,
+    ok: bool,
+) -> serenity::Result<()> {
+    settle(ctx, msg.id, msg.author.id, reply).await?;
+
+    let preview = parse(content).map_or_else(
+        |_| "(no code block)".to_string(),
+        |(_, code)| history::Entry::preview(&code),
+    );
+    let entry = history::Entry {
+        guild: msg.guild_id,
+        channel: msg.channel_id,
+        message: msg.id,
+        preview,
+        ok,
+    };
+
+    let data_read = ctx.data.read().await;
+    let history = data_read
+        .get::<Evaluations>()
+        .expect("Expected Evaluations in TypeMap.");
+    history.write().await.record(msg.author.id, entry);
+    Ok(())
+}
+
+/// Tells the tracker how sending or editing the reply of `source` went, see
+/// [`Tracker::settle`], passing the result on.
+async fn settle(
+    ctx: &Context,
+    source: MessageId,
+    author: UserId,
+    delivered: serenity::Result<Message>,
+) -> serenity::Result<Message> {
+    let entry = delivered
+        .as_ref()
+        .map(|reply| Tracked::new(reply.id, author, reply.channel_id));
+    tracker(ctx).await.write().await.settle(source, entry);
+    delivered
+}
+
+#[command]
+async fn example(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guard = begin_evaluation(ctx, msg.guild_id).await;
+    let content = match args.rest().trim() {
+        "" | "list" => {
+            let names: Vec<_> = examples::EXAMPLES
+                .iter()
+                .map(|(name, _)| format!("`{name}`"))
+                .collect();
+            format!("Available examples: {}", names.join(", "))
+        }
+        name => match (&guard, examples::get(name)) {
+            (Err(why), _) => why.to_string(),
+            (Ok(_), Some(source)) => {
+                info!("Evaluating example: {}", name);
+                let options = EvalOptions::default();
+                let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+                let start = Instant::now();
+                let limits = Limits::for_command(limits::Command::Example);
+                let result = evaluate(
+                    source,
+                    &msg.author.name,
+                    &options,
+                    &settings.sandbox,
+                    limits,
+                )
+                .await;
+                // The source is shown above the result, which makes do with the space left
+                let source = format!("```\n{source}```\n");
+                let elapsed = start.elapsed();
+                format::output_after(&source, result, &options, &settings, elapsed, "")
+            }
+            (Ok(_), None) => format!("Unknown example `{name}`, see `?example list`."),
+        },
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+async fn exports(ctx: &Context, msg: &Message) -> CommandResult {
+    let locale = settings(ctx, msg.guild_id).await.locale;
+    let content = match list_exports(&msg.content, &msg.author.name).await {
+        Ok(table) => format::block("Module exports:", &table),
+        Err(err) => format::block(i18n::text(&locale, "error_header"), &err.to_string()),
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+/// Shows everything one compilation produces: the WAT, the exports, the size and the output.
+#[command]
+async fn artifacts(ctx: &Context, msg: &Message) -> CommandResult {
+    let _guard = match begin_evaluation(ctx, msg.guild_id).await {
+        Ok(guard) => guard,
+        Err(why) => {
+            msg.reply(ctx, why).await?;
+            return Ok(());
+        }
+    };
+
+    let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+    let referenced = msg.referenced_message.as_deref();
+    let content = match resolve_source(&msg.content, referenced, &msg.attachments).await {
+        Ok(content) => content,
+        Err(err) => {
+            let header = i18n::text(&settings.locale, "attachment_error");
+            msg.reply(ctx, format::block(header, &err.to_string()))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let (options, compiled, result) =
+        match build_artifacts(&content, &msg.author.name, &settings.sandbox).await {
+            Ok(artifacts) => artifacts,
+            Err(err) => {
+                let header = i18n::text(&settings.locale, "error_header");
+                msg.reply(ctx, format::block(header, &err.to_string()))
+                    .await?;
+                return Ok(());
+            }
+        };
+
+    let wat = String::from_utf8_lossy(&compiled.wat);
+    let exports = runtime::describe_exports(&compiled.module);
+    let output = match result {
+        Ok(eval) => ansi::strip(&format::combined(&eval)),
+        Err(err) => format::error(&err, options.verbose, &settings.locale),
+    };
+
+    msg.channel_id
+        .send_message(ctx, |m| {
+            m.reference_message(msg).embed(|e| {
+                e.title("Artifacts")
+                    .field("WAT", format::field("wasm", &wat), false)
+                    .field("Exports", format::field("", &exports), false)
+                    .field("Module size", format!("{} bytes", compiled.wasm_size), true)
+                    .field("Output", format::field("", &output), false)
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+#[command]
+async fn explain(ctx: &Context, msg: &Message) -> CommandResult {
+    // As a reply to a result, explain the snippet that produced it
+    let fetched = match &msg.referenced_message {
+        Some(reply) => {
+            let lock = tracker(ctx).await;
+            let source = lock.read().await.source_of(reply.id);
+            let Some(source) = source else {
+                msg.reply(ctx, "That message is not a tracked evaluation result.")
+                    .await?;
+                return Ok(());
+            };
+
+            Some(msg.channel_id.message(ctx, source).await?)
+        }
+        None => None,
+    };
+
+    let source = fetched.as_ref().unwrap_or(msg);
+    let referenced = source.referenced_message.as_deref();
+    let content = match resolve_source(&source.content, referenced, &source.attachments).await {
+        Ok(content) => match compile(&content, &source.author.name).await {
+            Ok(_) => "This snippet compiles fine, there is nothing to explain.".to_string(),
+            Err(err) => format::block("Explanation:", &hints::explain(&err.to_string())),
+        },
+        Err(err) => {
+            let locale = settings(ctx, msg.guild_id).await.locale;
+            format::block(i18n::text(&locale, "attachment_error"), &err.to_string())
+        }
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+#[bucket = "gist"]
+async fn gist(ctx: &Context, msg: &Message) -> CommandResult {
+    let token = {
         let data_read = ctx.data.read().await;
-        let data_lock = data_read
-            .get::<BotData>()
-            .expect("Expected BotData in TypeMap.");
-        let mut hashmap = data_lock.write().await;
-        hashmap.insert(msg.id, reply.id);
+        data_read
+            .get::<GistToken>()
+            .expect("Expected GistToken in TypeMap.")
+            .clone()
+    };
+    let Some(token) = token else {
+        msg.reply(ctx, "Sharing as a gist is not configured on this bot.")
+            .await?;
+        return Ok(());
     };
 
+    let _guard = match begin_evaluation(ctx, msg.guild_id).await {
+        Ok(guard) => guard,
+        Err(why) => {
+            msg.reply(ctx, why).await?;
+            return Ok(());
+        }
+    };
+
+    let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+    let referenced = msg.referenced_message.as_deref();
+    let content = match resolve_source(&msg.content, referenced, &msg.attachments).await {
+        Ok(content) => content,
+        Err(err) => {
+            let header = i18n::text(&settings.locale, "attachment_error");
+            msg.reply(ctx, format::block(header, &err.to_string()))
+                .await?;
+            return Ok(());
+        }
+    };
+    let (options, code) = match parse(&content) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            let header = i18n::text(&settings.locale, "error_header");
+            msg.reply(ctx, format::block(header, &err.to_string()))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let limits = Limits::for_command(limits::Command::Eval);
+    let result = evaluate(&code, &msg.author.name, &options, &settings.sandbox, limits).await;
+    let output = match result {
+        Ok(eval) => format::combined(&eval),
+        Err(err) => format::error(&err, options.verbose, &settings.locale),
+    };
+
+    let content = match gist::upload(&token, &code, &output).await {
+        Ok(url) => format!("Shared as a gist: {url}"),
+        Err(err) => {
+            error!("Error creating a gist: {:?}", err);
+            format!("Could not create the gist: {err}")
+        }
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+#[bucket = "rerun"]
+async fn rerun(ctx: &Context, msg: &Message) -> CommandResult {
+    let lock = tracker(ctx).await;
+    let latest = lock.read().await.latest(msg.author.id, msg.channel_id);
+    let Some(source) = latest else {
+        msg.reply(ctx, "No recent evaluation to rerun.").await?;
+        return Ok(());
+    };
+
+    let _guard = match begin_evaluation(ctx, msg.guild_id).await {
+        Ok(guard) => guard,
+        Err(why) => {
+            msg.reply(ctx, why).await?;
+            return Ok(());
+        }
+    };
+
+    let source = msg.channel_id.message(ctx, source).await?;
+    info!("Rerunning message: {}", source.id);
+
+    let referenced = source.referenced_message.as_deref();
+    let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+    let content = match resolve_source(&source.content, referenced, &source.attachments).await {
+        Ok(content) => content,
+        Err(err) => {
+            let header = i18n::text(&settings.locale, "attachment_error");
+            msg.reply(ctx, format::block(header, &err.to_string()))
+                .await?;
+            return Ok(());
+        }
+    };
+    let footer = footer(ctx, source.guild_id, source.channel_id, source.id).await;
+    let (output, outcome) = compile_otput(&content, &msg.author.name, &settings, &footer).await;
+
+    msg.reply(ctx, output).await?;
+    record_outcome(ctx, msg.author.id, outcome).await;
+    Ok(())
+}
+
+#[command]
+async fn pin(ctx: &Context, msg: &Message) -> CommandResult {
+    let Some(reply) = &msg.referenced_message else {
+        msg.reply(ctx, "Reply to an evaluation result to pin it.")
+            .await?;
+        return Ok(());
+    };
+
+    let content = {
+        let lock = tracker(ctx).await;
+        let mut tracker = lock.write().await;
+        match tracker.by_reply_mut(reply.id) {
+            Some(entry) if entry.author == msg.author.id => {
+                entry.pinned = !entry.pinned;
+                if entry.pinned {
+                    "Pinned, edits will keep working on this result."
+                } else {
+                    "Unpinned, this result will expire as usual."
+                }
+            }
+            Some(_) => "Only the author of the snippet can pin it.",
+            None => "That message is not a tracked evaluation result.",
+        }
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+async fn history(ctx: &Context, msg: &Message) -> CommandResult {
+    // Only admins may look at someone else's history
+    let user = match msg.mentions.iter().find(|user| !user.bot) {
+        Some(user) if user.id != msg.author.id => {
+            if !is_owner(ctx, msg.author.id).await {
+                msg.reply(ctx, "You can only see your own history.").await?;
+                return Ok(());
+            }
+            user.id
+        }
+        _ => msg.author.id,
+    };
+
+    let content = {
+        let data_read = ctx.data.read().await;
+        let history = data_read
+            .get::<Evaluations>()
+            .expect("Expected Evaluations in TypeMap.");
+        let content = history.read().await.describe(user);
+        content
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+async fn selftest(ctx: &Context, msg: &Message) -> CommandResult {
+    let locale = settings(ctx, msg.guild_id).await.locale;
+    let content = match run_selftest().await {
+        Ok(output) => format::block(i18n::text(&locale, "selftest_passed"), &output),
+        Err(err) => format::block(i18n::text(&locale, "selftest_failed"), &err.to_string()),
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+async fn targets(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let check = args.rest().trim() == "--check";
+    let mut rows = Vec::new();
+    for target in TargetKind::ALL {
+        let status = if !check {
+            "available".to_string()
+        } else {
+            match check_target(target).await {
+                Ok(()) => "ok".to_string(),
+                Err(err) => format!("failed: {err}"),
+            }
+        };
+        rows.push(format!("{:<8}{status}", target.name()));
+    }
+
+    msg.reply(ctx, format::block("Targets:", &rows.join("\n")))
+        .await?;
+    Ok(())
+}
+
+#[command]
+#[owners_only]
+async fn cache(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let action = args.single::<String>().unwrap_or_default();
+    let key = args
+        .single::<String>()
+        .ok()
+        .and_then(|key| cache::parse_key(&key));
+
+    let content = match (action.as_str(), key, Runtime::get()) {
+        (_, _, Err(err)) => format::block("Runtime error:", &err.to_string()),
+        ("show", Some(key), Ok(runtime)) => match runtime.cached(key) {
+            Some(compiled) => {
+                let lines = [
+                    format!("key     {:016x}", compiled.key),
+                    format!("wasm    {} bytes", compiled.wasm_size),
+                    format!("wat     {} bytes", compiled.wat.len()),
+                    format!("exports {}", compiled.module.exports().count()),
+                ];
+                format::block("Cache entry:", &lines.join("\n"))
+            }
+            None => format!("No cached module with key `{key:016x}`."),
+        },
+        ("evict", Some(key), Ok(runtime)) => {
+            if runtime.evict(key) {
+                info!("Cache entry {:016x} evicted by {}", key, msg.author.tag());
+                format!("Evicted `{key:016x}`.")
+            } else {
+                format!("No cached module with key `{key:016x}`.")
+            }
+        }
+        _ => "Usage: `?cache show <key>` or `?cache evict <key>`.".to_string(),
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+async fn cachestats(ctx: &Context, msg: &Message) -> CommandResult {
+    let content = match Runtime::get() {
+        Ok(runtime) => format::block("Module cache:", &runtime.cache_stats()),
+        Err(err) => format::block("Runtime error:", &err.to_string()),
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+async fn latency(ctx: &Context, msg: &Message) -> CommandResult {
+    let content = match Runtime::get() {
+        Ok(runtime) => format::block("Evaluation latency:", &runtime.latency().describe()),
+        Err(err) => format::block("Runtime error:", &err.to_string()),
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+async fn whoami(ctx: &Context, msg: &Message) -> CommandResult {
+    let admin = is_owner(ctx, msg.author.id).await;
+    let state = if paused(ctx).await {
+        "paused"
+    } else {
+        "active"
+    };
+    let context = match msg.guild_id {
+        Some(guild) => format!("guild {guild}, channel {}", msg.channel_id),
+        None => format!("direct message, channel {}", msg.channel_id),
+    };
+
+    let prefix = settings(ctx, msg.guild_id).await.prefix;
+    let prefix = prefix.unwrap_or_else(|| PREFIX.to_string());
+
+    let mut lines = vec![
+        format!("user: {} ({})", msg.author.tag(), msg.author.id),
+        format!("context: {context}"),
+        format!("prefix: {prefix}"),
+        format!("admin: {}", if admin { "yes" } else { "no" }),
+        format!("evaluations: {state}"),
+    ];
+
+    if admin {
+        let tracked = tracker(ctx).await.read().await.len();
+        lines.push(format!("tracked results (admin): {tracked}"));
+    }
+
+    let content = format::block("Effective settings:", &lines.join("\n"));
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+async fn limits(ctx: &Context, msg: &Message) -> CommandResult {
+    let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+    let limits = Limits::for_command(limits::Command::Eval);
+    let timeout = limits
+        .timeout
+        .map_or_else(|| "none".to_string(), |timeout| format!("{timeout:?}"));
+
+    let lines = [
+        format!("run timeout: {timeout}"),
+        format!("compile timeout: {:?}", limits::COMPILE_TIMEOUT),
+        format!("memory: {}", runtime::show_pages(limits.memory_pages)),
+        format!("fuel: {}", runtime::FUEL),
+        format!("reply size: {} bytes", format::MESSAGE_LIMIT),
+        format!("output lines: {}", settings.max_lines),
+        format!("attached source size: {} bytes", content::MAX_SIZE),
+        format!(
+            "safe mode: {}",
+            if settings.sandbox.safe { "on" } else { "off" }
+        ),
+    ];
+
+    msg.reply(ctx, format::block("Active limits:", &lines.join("\n")))
+        .await?;
+    Ok(())
+}
+
+/// Shows what a program run in this channel gets from WASI.
+#[command]
+async fn env(ctx: &Context, msg: &Message) -> CommandResult {
+    let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+    let content = format::block("WASI environment:", &settings.sandbox.describe());
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn config(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let Some(guild) = msg.guild_id else {
+        return Ok(());
+    };
+
+    if !can_configure(ctx, msg).await {
+        msg.reply(
+            ctx,
+            "Changing settings requires the Manage Server permission.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let lock = {
+        let data_read = ctx.data.read().await;
+        data_read
+            .get::<GuildConfig>()
+            .expect("Expected GuildConfig in TypeMap.")
+            .clone()
+    };
+
+    let content = match (args.single::<String>(), args.rest().trim()) {
+        (Ok(key), value) if !value.is_empty() => {
+            let mut settings = lock.write().await;
+            match settings.guild_mut(guild).set(&key, value) {
+                Ok(()) => {
+                    info!("Guild {} set {} = {}", guild, key, value);
+                    format!("Set `{key}` to `{value}`.")
+                }
+                Err(err) => err.to_string(),
+            }
+        }
+        _ => {
+            let settings = lock.read().await.get(Some(guild));
+            format::block("Settings:", &settings.describe())
+        }
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+#[owners_only]
+async fn pause(ctx: &Context, msg: &Message) -> CommandResult {
+    set_paused(ctx, true).await;
+    info!("Evaluations paused by {}", msg.author.tag());
+
+    msg.reply(ctx, "Evaluations paused.").await?;
+    Ok(())
+}
+
+/// Lifts the mute of a user who kept hitting the resource limits.
+#[command]
+#[owners_only]
+async fn unmute(ctx: &Context, msg: &Message) -> CommandResult {
+    let Some(user) = msg.mentions.iter().find(|user| !user.bot) else {
+        msg.reply(ctx, "Mention the user to unmute.").await?;
+        return Ok(());
+    };
+
+    let content = if offenders(ctx).await.lock().await.unmute(user.id) {
+        info!("{} unmuted by {}", user.tag(), msg.author.tag());
+        format!("{} can evaluate again.", user.tag())
+    } else {
+        format!("{} is not muted.", user.tag())
+    };
+
+    msg.reply(ctx, content).await?;
+    Ok(())
+}
+
+#[command]
+#[owners_only]
+async fn resume(ctx: &Context, msg: &Message) -> CommandResult {
+    set_paused(ctx, false).await;
+    info!("Evaluations resumed by {}", msg.author.tag());
+
+    msg.reply(ctx, "Evaluations resumed.").await?;
     Ok(())
 }
 
 async fn edit(ctx: &Context, msg: MessageUpdateEvent, id: MessageId) -> CommandResult {
+    // Updates that leave the content untouched, like embeds loading, need no new result
+    let Some(message) = &msg.content else {
+        return Ok(());
+    };
+
+    if !first_delivery(ctx, msg.id, msg.edited_timestamp).await {
+        return Ok(());
+    }
+
+    // Edits don't go through the framework, so its mute check is repeated here. Updates may
+    // leave the author out, but the tracked result knows whose it is
+    let tracked = tracker(ctx)
+        .await
+        .read()
+        .await
+        .get(&msg.id)
+        .map(|entry| entry.author);
+    let Some(author) = msg.author.as_ref().map(|user| user.id).or(tracked) else {
+        return Ok(());
+    };
+    if muted(ctx, author).await {
+        return Ok(());
+    }
+
+    // Updates only carry the attachments when they changed, yet one may hold the source. It
+    // is only read without a code block, otherwise fetching the message would be wasted
+    let referenced = msg.referenced_message.as_ref().and_then(Option::as_deref);
+    let attachments = match &msg.attachments {
+        Some(attachments) => attachments.clone(),
+        None if parser::first_block(&with_referenced(message, referenced)).is_none() => {
+            msg.channel_id.message(ctx, msg.id).await?.attachments
+        }
+        None => Vec::new(),
+    };
+
+    let settings = settings(ctx, msg.guild_id).await.in_channel(msg.channel_id);
+    let message = &match resolve_source(message, referenced, &attachments).await {
+        Ok(message) => message,
+        Err(err) => {
+            let header = i18n::text(&settings.locale, "attachment_error");
+            let content = format::block(header, &err.to_string());
+            let edited = msg
+                .channel_id
+                .edit_message(ctx, id, |m| m.content(content))
+                .await;
+            settle(ctx, msg.id, author, edited).await?;
+            return Ok(());
+        }
+    };
+
+    // Clearing the code out of the message takes the result back
+    if settings.retract_on_clear && parser::first_block(message).is_none() {
+        info!("Retracting reply: {}", id);
+        let deleted = msg.channel_id.delete_message(ctx, id).await;
+        if deleted.as_ref().map_or_else(tracking::is_gone, |()| true) {
+            tracker(ctx).await.write().await.remove(&msg.id);
+        }
+        deleted?;
+        return Ok(());
+    }
+
+    let _guard = match begin_evaluation(ctx, msg.guild_id).await {
+        Ok(guard) => guard,
+        Err(why) => {
+            let edited = msg
+                .channel_id
+                .edit_message(ctx, id, |m| m.content(why))
+                .await;
+            settle(ctx, msg.id, author, edited).await?;
+            return Ok(());
+        }
+    };
+
     info!("Evaluating edited message: {}", id);
 
     let name = &msg.author.map_or_else(String::new, |user| user.name);
-    let message = &msg
-        .content
-        .with_context(|| "Failed to get the msg content")?;
+    let footer = footer(ctx, msg.guild_id, msg.channel_id, msg.id).await;
+    let (output, outcome) = compile_otput(message, name, &settings, &footer).await;
+    record_outcome(ctx, author, outcome).await;
 
-    let output = compile_otput(message, name);
+    if settings.edit_mode == EditMode::InPlace {
+        let edited = msg
+            .channel_id
+            .edit_message(ctx, id, |m| m.content(output))
+            .await;
+        settle(ctx, msg.id, author, edited).await?;
+        return Ok(());
+    }
 
-    msg.channel_id
-        .edit_message(ctx, id, |m| m.content(output))
-        .await?;
+    // A new reply that fails to be sent leaves the old one tracked, as it still holds a result
+    let reply = msg
+        .channel_id
+        .send_message(ctx, |m| {
+            m.content(output)
+                .reference_message((msg.channel_id, msg.id))
+        })
+        .await;
+    settle(ctx, msg.id, author, reply).await?;
+
+    // The new reply is already tracked, failing to clean up the old one is not fatal
+    if settings.delete_old_reply {
+        if let Err(why) = msg.channel_id.delete_message(ctx, id).await {
+            error!("Error deleting the previous reply: {:?}", why);
+        }
+    }
     Ok(())
 }
 
-fn compile_otput(message: &str, name: &str) -> String {
-    match compile(message, name) {
-        Ok(ok) => format!("Compilation result:\n```\n{ok}\n```"),
-        Err(err) => format!("Compilation error:\n```\n{err}\n```"),
+/// How an evaluation ended.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Ok,
+    Failed,
+    ResourceLimit,
+}
+
+impl Outcome {
+    fn of(result: &Result<Evaluation>) -> Self {
+        match result {
+            Ok(_) => Self::Ok,
+            Err(err) if abuse::is_resource_limit(err) => Self::ResourceLimit,
+            Err(_) => Self::Failed,
+        }
+    }
+}
+
+/// Evaluates a message, returning the reply and how the evaluation ended.
+async fn compile_otput(
+    message: &str,
+    name: &str,
+    settings: &GuildSettings,
+    footer: &str,
+) -> (String, Outcome) {
+    let start = Instant::now();
+    let (options, result) = match parse(message) {
+        Ok((options, code)) => {
+            let limits = Limits::for_command(limits::Command::Eval);
+            let result = evaluate(&code, name, &options, &settings.sandbox, limits).await;
+            (options, result)
+        }
+        Err(err) => (EvalOptions::default(), Err(err)),
+    };
+
+    let outcome = Outcome::of(&result);
+    let output = format::output(result, &options, settings, start.elapsed(), footer);
+    (output, outcome)
+}
+
+/// The text to evaluate, by order of precedence: the message itself when it has a code
+/// block, else the message followed by the first block of the message it replies to.
+///
+/// Messages from bots are never used as a source, so results can't be evaluated again.
+fn with_referenced<'a>(content: &'a str, referenced: Option<&Message>) -> Cow<'a, str> {
+    if parser::first_block(content).is_some() {
+        return Cow::Borrowed(content);
+    }
+
+    let block = referenced
+        .filter(|referenced| !referenced.author.bot)
+        .and_then(|referenced| parser::first_block(&referenced.content));
+
+    match block {
+        Some(block) => Cow::Owned(format!("{content}\n{block}")),
+        None => Cow::Borrowed(content),
+    }
+}
+
+/// The text to evaluate, as [`with_referenced`] finds it, or else the message followed by the
+/// source of its attached file. An attachment stands in for a code block but never overrides
+/// one.
+///
+/// Fails only when the attachment could not be read.
+async fn resolve_source<'a>(
+    content: &'a str,
+    referenced: Option<&Message>,
+    attachments: &[Attachment],
+) -> Result<Cow<'a, str>> {
+    let content = with_referenced(content, referenced);
+    match attachment::source_file(attachments) {
+        Some(file) if parser::first_block(&content).is_none() => {
+            let source = attachment::download(file).await?;
+            Ok(Cow::Owned(attachment::with_source(&content, &source)))
+        }
+        _ => Ok(content),
     }
 }
 
-fn compile(msg: &str, name: &str) -> Result<String> {
-    let trimmed = msg
-        .strip_prefix("?eval")
-        .map(|s| s.trim_start())
-        .and_then(|s| s.strip_prefix("```"))
-        .and_then(|s| s.strip_suffix("```"))
-        .with_context(|| "Failed to parse a code block")?;
+fn parse(msg: &str) -> Result<(EvalOptions, String)> {
+    let text = parser::strip_command(msg);
+    let (flags, code) =
+        parser::split_block(text).with_context(|| "Failed to parse a code block")?;
+
+    let options = EvalOptions::parse(flags)?;
+    let code = if options.join {
+        parser::join_blocks(text)
+    } else {
+        code
+    };
+
+    Ok((options, code))
+}
+
+async fn compile(msg: &str, name: &str) -> Result<Arc<Compiled>> {
+    let (options, code) = parse(msg)?;
+    compile_snippet(&code, &options.module_name(name)).await
+}
+
+async fn list_exports(msg: &str, name: &str) -> Result<String> {
+    let compiled = compile(msg, name).await?;
+    Ok(runtime::describe_exports(&compiled.module))
+}
 
-    let reader = &mut BufReader::new(trimmed.as_bytes());
-    let mut writer = BufWriter::new(vec![]);
+pub struct Evaluation {
+    pub output: String,
+    pub stderr: String,
+    pub exit: i32,
+    pub stats: Option<Stats>,
+    /// Whether a second run printed the same, when asked for with `--verify-deterministic`.
+    pub verified: bool,
+    /// Whether the program ran in safe mode, without any I/O.
+    pub safe: bool,
+}
 
-    ashfire::compile_buffer(name, reader, &mut writer, Target::Wasi, true)?;
+pub struct Stats {
+    pub module_size: usize,
+    pub output_size: usize,
+    pub seed: Option<u64>,
+    pub key: u64,
+    /// Compilation steps and their duration, when asked for with `--phases`.
+    pub phases: Option<Vec<(&'static str, Duration)>>,
+    pub run: RunStats,
+}
 
-    let output = writer.into_inner()?;
-    run(&output)
+async fn evaluate(
+    source: &str,
+    name: &str,
+    options: &EvalOptions,
+    sandbox: &Sandbox,
+    limits: Limits,
+) -> Result<Evaluation> {
+    let pipes = Pipes::new(options.merge_streams);
+    let sandbox = &sandbox.with_seed(options.seed);
+    let name = &options.module_name(name);
+    let (compiled, run) = execute(source, name, &pipes, sandbox, limits).await?;
+    conclude(&compiled, run, &pipes, options, sandbox, limits).await
 }
 
-fn run(wat: &[u8]) -> Result<String> {
-    let engine = Engine::default();
-    let mut linker = Linker::new(&engine);
-    wasmtime_wasi::add_to_linker(&mut linker, |s| s)?;
+/// Gathers the result of a run, first running it again when it should be deterministic.
+async fn conclude(
+    compiled: &Compiled,
+    run: RunStats,
+    pipes: &Pipes,
+    options: &EvalOptions,
+    sandbox: &Sandbox,
+    limits: Limits,
+) -> Result<Evaluation> {
+    let mut eval = finish(compiled, run, pipes, options)?;
 
-    let writer = wasi_common::pipe::WritePipe::new_in_memory();
-    let wasi = WasiCtxBuilder::new()
-        .stdout(Box::new(writer.clone()))
-        .build();
+    if options.verify_deterministic {
+        verify_deterministic(compiled, &eval, options, sandbox, limits).await?;
+        eval.verified = true;
+    }
+    Ok(eval)
+}
 
-    {
-        let mut store = Store::new(&engine, wasi);
-        let module = Module::new(&engine, wat)?;
+/// Runs the module again in a fresh store, failing with a short diff if it printed anything
+/// different the second time.
+async fn verify_deterministic(
+    compiled: &Compiled,
+    first: &Evaluation,
+    options: &EvalOptions,
+    sandbox: &Sandbox,
+    limits: Limits,
+) -> Result<()> {
+    let pipes = Pipes::new(options.merge_streams);
+    let run = run_module(&compiled.module, &pipes, sandbox, limits).await?;
+    let second = finish(compiled, run, &pipes, options)?;
 
-        linker.module(&mut store, "", &module)?;
-        linker
-            .get_default(&mut store, "")?
-            .typed::<(), ()>(&store)?
-            .call(&mut store, ())?;
+    let (before, after) = (format::combined(first), format::combined(&second));
+    if before == after && first.exit == second.exit {
+        return Ok(());
     }
 
-    let vec = writer
-        .try_into_inner()
-        .expect("sole remaining reference to WritePipe")
-        .into_inner();
+    let mut diff = format::diff(&before, &after);
+    if first.exit != second.exit {
+        diff += &format!("\nexit status: {} then {}", first.exit, second.exit);
+    }
+    Err(anyhow!("The output differs between two runs:\n{diff}"))
+}
+
+/// Compiles a message once, then runs the module, keeping every intermediate result.
+async fn build_artifacts(
+    message: &str,
+    name: &str,
+    sandbox: &Sandbox,
+) -> Result<(EvalOptions, Arc<Compiled>, Result<Evaluation>)> {
+    let (options, code) = parse(message)?;
+    let compiled = compile_snippet(&code, &options.module_name(name)).await?;
+
+    let pipes = Pipes::new(options.merge_streams);
+    let sandbox = &sandbox.with_seed(options.seed);
+    let limits = Limits::for_command(limits::Command::Eval);
+    let result = run_module(&compiled.module, &pipes, sandbox, limits)
+        .await
+        .and_then(|run| finish(&compiled, run, &pipes, &options));
+
+    Ok((options, compiled, result))
+}
+
+async fn evaluate_wat(wat: &str, options: &EvalOptions, sandbox: &Sandbox) -> Result<Evaluation> {
+    let wat = wat.to_string();
+    let compiled = blocking(move || Runtime::get()?.assemble(&wat)).await?;
+
+    let pipes = Pipes::new(options.merge_streams);
+    let sandbox = &sandbox.with_seed(options.seed);
+    let limits = Limits::for_command(limits::Command::Eval);
+    let run = run_module(&compiled.module, &pipes, sandbox, limits).await?;
+    finish(&compiled, run, &pipes, options)
+}
+
+async fn execute(
+    source: &str,
+    name: &str,
+    pipes: &Pipes,
+    sandbox: &Sandbox,
+    limits: Limits,
+) -> Result<(Arc<Compiled>, RunStats)> {
+    let latency = Runtime::get()?.latency();
+
+    let start = Instant::now();
+    let compiled = compile_snippet(source, name).await?;
+    let compiled_at = start.elapsed();
+    latency.compile.record(compiled_at);
+
+    let run = run_module(&compiled.module, pipes, sandbox, limits).await;
+    latency.run.record(start.elapsed() - compiled_at);
+    latency.total.record(start.elapsed());
+    Ok((compiled, run?))
+}
+
+fn finish(
+    compiled: &Compiled,
+    run: RunStats,
+    pipes: &Pipes,
+    options: &EvalOptions,
+) -> Result<Evaluation> {
+    let (stdout, stderr) = (pipes.stdout.contents(), pipes.stderr_contents());
+    let stats = options.stats.then(|| Stats {
+        module_size: compiled.wasm_size,
+        output_size: stdout.len() + stderr.len(),
+        seed: options.seed,
+        key: compiled.key,
+        phases: options.phases.then(|| compiled.phases.clone()),
+        run,
+    });
+
+    Ok(Evaluation {
+        output: options.encoding.encode(stdout)?,
+        stderr: options.encoding.encode(stderr)?,
+        exit: run.exit,
+        stats,
+        verified: false,
+        safe: run.safe,
+    })
+}
+
+/// Compiles and runs the self-test snippet for `target`.
+async fn check_target(target: TargetKind) -> Result<()> {
+    let compile =
+        blocking(move || Runtime::get()?.compile_for(examples::HELLO, "selftest", target));
+    let module = with_compile_timeout(compile).await?;
+
+    let limits = Limits::for_command(limits::Command::SelfTest);
+    run_module(&module, &Pipes::new(false), &Sandbox::default(), limits).await?;
+    Ok(())
+}
+
+/// Compiles and runs a known-good snippet to check the whole pipeline works.
+async fn run_selftest() -> Result<String> {
+    let compiled = compile_snippet(examples::HELLO, "selftest").await?;
+
+    let pipes = Pipes::new(false);
+    let limits = Limits::for_command(limits::Command::SelfTest);
+    run_module(&compiled.module, &pipes, &Sandbox::default(), limits).await?;
+    Ok(String::from_utf8_lossy(&pipes.stdout.contents()).into_owned())
+}
+
+/// Runs CPU-bound work on the blocking pool, so that it never holds up the event handlers.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(work).await?
+}
+
+/// Compiles a snippet off the async runtime, through the module cache.
+async fn compile_snippet(source: &str, name: &str) -> Result<Arc<Compiled>> {
+    let (source, name) = (source.to_string(), name.to_string());
+    with_compile_timeout(blocking(move || Runtime::get()?.compile(&source, &name))).await
+}
+
+/// Gives up on a compiler stuck on some input, so that it can't hold the evaluation forever.
+/// The stuck compilation can't be killed and is left to finish on its own.
+async fn with_compile_timeout<T>(compile: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(limits::COMPILE_TIMEOUT, compile)
+        .await
+        .unwrap_or_else(|_| Err(CompileTimeout(limits::COMPILE_TIMEOUT).into()))
+}
 
-    let output = String::from_utf8_lossy(&vec).to_string();
-    Ok(output)
+/// Runs a module off the async runtime, its own limits bounding how long that takes.
+async fn run_module(
+    module: &Module,
+    pipes: &Pipes,
+    sandbox: &Sandbox,
+    limits: Limits,
+) -> Result<RunStats> {
+    let (module, pipes, sandbox) = (module.clone(), pipes.clone(), sandbox.clone());
+    blocking(move || Runtime::get()?.run(&module, &pipes, &sandbox, limits)).await
 }
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// How often the engine's epoch advances, which is the granularity of timeouts.
+pub const TICK: Duration = Duration::from_millis(10);
+
+const STANDARD_TIMEOUT: Duration = Duration::from_secs(5);
+/// 64 MiB, in WASM pages of 64 KiB.
+const STANDARD_MEMORY_PAGES: usize = 1024;
+
+/// How long ashfire may take to compile a snippet, whatever the command.
+pub const COMPILE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The kinds of command that run a program.
+#[derive(Clone, Copy)]
+pub enum Command {
+    Eval,
+    Example,
+    SelfTest,
+}
+
+/// What a single run may use before it is stopped.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    /// Pages a memory may grow to, growing further stops the run.
+    pub memory_pages: usize,
+}
+
+impl Limits {
+    /// The limits each command runs with, kept in one place so they are easy to tune.
+    pub fn for_command(command: Command) -> Self {
+        let timeout = match command {
+            Command::Eval | Command::Example | Command::SelfTest => Some(STANDARD_TIMEOUT),
+        };
+
+        let memory_pages = match command {
+            Command::Eval | Command::Example | Command::SelfTest => STANDARD_MEMORY_PAGES,
+        };
+
+        Self {
+            timeout,
+            memory_pages,
+        }
+    }
+
+    /// The timeout as a number of epoch ticks, if any.
+    pub fn deadline(&self) -> Option<u64> {
+        self.timeout
+            .map(|timeout| (timeout.as_millis() / TICK.as_millis()).max(1) as u64)
+    }
+}
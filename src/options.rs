@@ -0,0 +1,140 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+#[derive(Default)]
+pub struct EvalOptions {
+    pub encoding: Encoding,
+    pub color: Color,
+    pub seed: Option<u64>,
+    pub name: Option<String>,
+    pub stats: bool,
+    pub merge_streams: bool,
+    pub stream: bool,
+    pub verbose: bool,
+    pub join: bool,
+    pub phases: bool,
+    pub verify_deterministic: bool,
+}
+
+impl EvalOptions {
+    /// Parses the `--flag=value` arguments given between the command and the code block.
+    pub fn parse(flags: &str) -> Result<Self> {
+        let mut options = Self::default();
+
+        for flag in flags.split_whitespace() {
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (flag, None),
+            };
+
+            match (key, value) {
+                ("--encoding", Some(value)) => options.encoding = value.parse()?,
+                ("--color", Some(value)) => options.color = value.parse()?,
+                ("--name", Some(value)) => options.name = Some(validate_name(value)?),
+                ("--seed", Some(value)) => {
+                    let seed = value
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid seed `{value}`"))?;
+                    options.seed = Some(seed);
+                }
+                ("--stats", None) => options.stats = true,
+                ("--merge-streams", None) => options.merge_streams = true,
+                ("--stream", None) => options.stream = true,
+                ("--verbose", None) => options.verbose = true,
+                ("--join", None) => options.join = true,
+                ("--phases", None) => options.phases = true,
+                ("--verify-deterministic", None) => options.verify_deterministic = true,
+                _ => bail!("Unknown flag: `{flag}`"),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// The module name given to the compiler: the `--name` override, or else a sanitized
+    /// version of the author's name.
+    pub fn module_name(&self, author: &str) -> String {
+        self.name.clone().unwrap_or_else(|| sanitize_name(author))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn validate_name(name: &str) -> Result<String> {
+    if name.is_empty() || !name.chars().all(is_name_char) {
+        bail!("Invalid name `{name}`, only letters, digits and underscores are allowed");
+    }
+
+    Ok(name.to_string())
+}
+
+fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if is_name_char(c) { c } else { '_' })
+        .collect();
+
+    if name.is_empty() {
+        "snippet".to_string()
+    } else {
+        name
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum Encoding {
+    #[default]
+    Lossy,
+    Strict,
+    Base64,
+}
+
+impl Encoding {
+    pub fn encode(self, output: Vec<u8>) -> Result<String> {
+        match self {
+            Self::Lossy => Ok(String::from_utf8_lossy(&output).into_owned()),
+            Self::Strict => String::from_utf8(output).map_err(|err| {
+                let at = err.utf8_error().valid_up_to();
+                anyhow!("Output is not valid UTF-8: invalid byte sequence at offset {at}")
+            }),
+            Self::Base64 => Ok(STANDARD.encode(output)),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lossy" => Ok(Self::Lossy),
+            "strict" => Ok(Self::Strict),
+            "base64" => Ok(Self::Base64),
+            _ => bail!("Unknown encoding `{s}`, expected one of: lossy, strict, base64"),
+        }
+    }
+}
+
+/// What to do with the ANSI escape sequences a program prints.
+#[derive(Clone, Copy, Default)]
+pub enum Color {
+    #[default]
+    Strip,
+    Ansi,
+}
+
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strip" => Ok(Self::Strip),
+            "ansi" => Ok(Self::Ansi),
+            _ => bail!("Unknown color mode `{s}`, expected one of: strip, ansi"),
+        }
+    }
+}
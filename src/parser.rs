@@ -0,0 +1,238 @@
+/// Characters a Markdown fence can be made of, a block is closed by the same kind.
+const FENCE_CHARS: [u8; 2] = [b'`', b'~'];
+const MIN_FENCE: usize = 3;
+
+/// Drops the command invocation (prefix or bot mention, and command name) from the start
+/// of a message.
+pub fn strip_command(msg: &str) -> &str {
+    let msg = strip_mention(msg.trim_start()).trim_start();
+    let end = msg
+        .find(|c: char| c.is_whitespace() || c == '`' || c == '~')
+        .unwrap_or(msg.len());
+
+    &msg[end..]
+}
+
+/// The framework only dispatches mentions of the bot itself, so any leading user mention
+/// is the invocation.
+fn strip_mention(msg: &str) -> &str {
+    let Some(rest) = msg.strip_prefix("<@") else {
+        return msg;
+    };
+
+    let rest = rest.strip_prefix('!').unwrap_or(rest);
+    match rest.split_once('>') {
+        Some((id, after)) if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) => after,
+        _ => msg,
+    }
+}
+
+/// Splits the text following a command into its flags and the source inside its code block.
+///
+/// Line endings are normalized, a language tag on the opening line is dropped (mirroring how
+/// Discord renders it) and blank lines around the source are trimmed.
+pub fn split_block(text: &str) -> Option<(&str, String)> {
+    let (open, close) = find_block(text)?;
+    let (flags, code) = (&text[..open.start], &text[open.end()..close.start]);
+    Some((flags, body(code)))
+}
+
+/// Concatenates the sources of every code block in `text`, in order, as a single program.
+pub fn join_blocks(text: &str) -> String {
+    let mut sources = Vec::new();
+    let mut rest = text;
+    while let Some((open, close)) = find_block(rest) {
+        sources.push(body(&rest[open.end()..close.start]));
+        rest = &rest[close.end()..];
+    }
+
+    sources.join("\n")
+}
+
+fn body(code: &str) -> String {
+    let code = code.replace("\r\n", "\n");
+    let body = match code.split_once('\n') {
+        Some((tag, body)) if is_language_tag(tag) => body,
+        _ => code.as_str(),
+    };
+
+    trim_blank_lines(body).to_string()
+}
+
+/// Returns the first complete code block in `text`, fences included.
+pub fn first_block(text: &str) -> Option<&str> {
+    let (open, close) = find_block(text)?;
+    Some(&text[open.start..close.end()])
+}
+
+/// A run of the same fence character.
+#[derive(Clone, Copy)]
+struct Run {
+    start: usize,
+    len: usize,
+}
+
+impl Run {
+    fn end(self) -> usize {
+        self.start + self.len
+    }
+}
+
+/// Finds the opening and closing fences of the block opened by the earliest fence.
+///
+/// As in CommonMark, a fence of N characters is only closed by a run of at least N of the
+/// same character, so shorter runs inside the code are kept as they are.
+fn find_block(text: &str) -> Option<(Run, Run)> {
+    let (fence, open) = FENCE_CHARS
+        .into_iter()
+        .filter_map(|fence| {
+            let open = runs(text, fence, 0).find(|run| run.len >= MIN_FENCE)?;
+            Some((fence, open))
+        })
+        .min_by_key(|(_, open)| open.start)?;
+
+    let close = runs(text, fence, open.end()).find(|run| run.len >= open.len)?;
+
+    Some((open, close))
+}
+
+/// The maximal runs of `fence` in `text`, starting at byte `from`.
+fn runs(text: &str, fence: u8, from: usize) -> impl Iterator<Item = Run> + '_ {
+    let bytes = text.as_bytes();
+    let mut i = from;
+
+    std::iter::from_fn(move || {
+        i += bytes.get(i..)?.iter().position(|&b| b == fence)?;
+        let start = i;
+        while bytes.get(i) == Some(&fence) {
+            i += 1;
+        }
+
+        Some(Run {
+            start,
+            len: i - start,
+        })
+    })
+}
+
+fn is_language_tag(line: &str) -> bool {
+    line.trim().is_empty() || !line.contains(char::is_whitespace)
+}
+
+fn trim_blank_lines(code: &str) -> &str {
+    let start = code
+        .find(|c: char| !c.is_whitespace())
+        .map_or(code.len(), |first| {
+            code[..first].rfind('\n').map_or(0, |newline| newline + 1)
+        });
+
+    code[start..].trim_end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_a_prefixed_command() {
+        assert_eq!(strip_command("?eval ```1```"), " ```1```");
+        assert_eq!(strip_command("!!eval --stats ```1```"), " --stats ```1```");
+        assert_eq!(strip_command("  ;eval```1```"), "```1```");
+    }
+
+    #[test]
+    fn strips_a_mention_prefix() {
+        assert_eq!(strip_command("<@123> eval ```1```"), " ```1```");
+        assert_eq!(strip_command("<@!123>eval ```1```"), " ```1```");
+        // Not a user mention, so it is taken as the command itself
+        assert_eq!(strip_command("<@abc> eval"), " eval");
+    }
+
+    #[test]
+    fn normalizes_line_endings() {
+        let (flags, code) = split_block("```\r\n1 print\r\n2 print\r\n```").unwrap();
+        assert_eq!(flags, "");
+        assert_eq!(code, "1 print\n2 print");
+    }
+
+    #[test]
+    fn trims_blank_lines_but_not_indentation() {
+        let (_, code) = split_block("```\n\n  1 print\n\n\n```").unwrap();
+        assert_eq!(code, "  1 print");
+    }
+
+    #[test]
+    fn drops_a_language_tag() {
+        let (_, code) = split_block("```fire\n1 print\n```").unwrap();
+        assert_eq!(code, "1 print");
+    }
+
+    #[test]
+    fn keeps_code_on_the_fence_line() {
+        let (_, code) = split_block("```1 print\n2 print\n```").unwrap();
+        assert_eq!(code, "1 print\n2 print");
+
+        let (_, code) = split_block("```1 print```").unwrap();
+        assert_eq!(code, "1 print");
+    }
+
+    #[test]
+    fn separates_flags_from_the_block() {
+        let (flags, code) = split_block(" --stats --verbose ```1 print```").unwrap();
+        assert_eq!(flags, " --stats --verbose ");
+        assert_eq!(code, "1 print");
+    }
+
+    #[test]
+    fn accepts_tilde_fences() {
+        let (_, code) = split_block("~~~fire\n1 print\n~~~").unwrap();
+        assert_eq!(code, "1 print");
+    }
+
+    #[test]
+    fn closes_a_block_with_the_same_fence_character() {
+        let (_, code) = split_block("~~~\n```\n~~~").unwrap();
+        assert_eq!(code, "```");
+
+        let (_, code) = split_block("```\n~~~\n```").unwrap();
+        assert_eq!(code, "~~~");
+
+        assert!(split_block("~~~\n1 print\n```").is_none());
+    }
+
+    #[test]
+    fn closes_on_the_first_long_enough_fence() {
+        let (_, code) = split_block("````\n```nested```\n````").unwrap();
+        assert_eq!(code, "```nested```");
+
+        // A longer run closes the block too
+        let (_, code) = split_block("```1 print`````").unwrap();
+        assert_eq!(code, "1 print");
+    }
+
+    #[test]
+    fn takes_only_the_first_block() {
+        let (flags, code) = split_block("```1 print``` prose ```2 print```").unwrap();
+        assert_eq!(flags, "");
+        assert_eq!(code, "1 print");
+        assert_eq!(
+            first_block("see ```1 print``` and ```2 print```"),
+            Some("```1 print```")
+        );
+    }
+
+    #[test]
+    fn joins_every_block_in_order() {
+        let text = "--join ```fire\nproc main in\n```\nthen\n~~~\n  1 print\n~~~ and ```end```";
+        assert_eq!(join_blocks(text), "proc main in\n  1 print\nend");
+        assert_eq!(join_blocks("no blocks"), "");
+    }
+
+    #[test]
+    fn recognizes_language_tags() {
+        assert!(is_language_tag(""));
+        assert!(is_language_tag("  "));
+        assert!(is_language_tag("fire"));
+        assert!(!is_language_tag("1 print"));
+    }
+}
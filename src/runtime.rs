@@ -0,0 +1,779 @@
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{self, BufReader, BufWriter, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use ashfire::target::Target;
+use rand::{rngs::StdRng, SeedableRng};
+use tracing::{debug, info};
+use wasi_common::{pipe::WritePipe, I32Exit};
+use wasmtime::*;
+use wasmtime_wasi::{sync::WasiCtxBuilder, WasiCtx};
+
+use crate::{
+    cache::{self, CacheStats, Compiled, ModuleCache},
+    latency::Latency,
+    limits::{self, Limits},
+};
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// The ashfire version snippets are compiled with.
+pub const COMPILER: &str = env!("ASHFIRE_VERSION");
+
+/// Fuel given to each run, only there to be accounted for.
+pub const FUEL: u64 = u64::MAX / 2;
+const PAGE_SIZE: usize = 64 * 1024;
+
+/// The `Engine` and WASI `Linker` shared by every evaluation.
+pub struct Runtime {
+    engine: Engine,
+    linker: Linker<Host>,
+    cache: Mutex<ModuleCache>,
+    stats: CacheStats,
+    latency: Latency,
+}
+
+impl Runtime {
+    pub(crate) fn new() -> Result<Self> {
+        // Backtraces are only captured when a trap happens, so this has no cost on the happy path
+        let mut config = Config::new();
+        config
+            .wasm_backtrace(true)
+            .consume_fuel(true)
+            .epoch_interruption(true);
+
+        let engine = Engine::new(&config)?;
+
+        // Advancing the epoch on a fixed tick is what lets runs time out
+        let ticker = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(limits::TICK);
+            ticker.increment_epoch();
+        });
+
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |host: &mut Host| &mut host.wasi)
+            .with_context(|| "Could not link WASI")?;
+        info!("WASI linked into the shared runtime");
+
+        Ok(Self {
+            engine,
+            linker,
+            cache: Mutex::default(),
+            stats: CacheStats::default(),
+            latency: Latency::default(),
+        })
+    }
+
+    /// Returns the shared runtime, building it on first use.
+    pub fn get() -> Result<&'static Self> {
+        if let Some(runtime) = RUNTIME.get() {
+            return Ok(runtime);
+        }
+
+        let runtime = Self::new()?;
+        Ok(RUNTIME.get_or_init(|| runtime))
+    }
+
+    /// Compiles a snippet, reusing the cached module when the same source was compiled before.
+    ///
+    /// This blocks for as long as ashfire takes, which has no bound of its own.
+    pub fn compile(&self, source: &str, name: &str) -> Result<Arc<Compiled>> {
+        let key = cache::key(source, name);
+        if let Some(compiled) = self.cache().get(key) {
+            debug!("Module cache hit: {:016x}", key);
+            self.stats.hit();
+            return Ok(compiled);
+        }
+
+        self.stats.miss();
+
+        // ashfire doesn't report its own phases, so it is timed as a whole
+        let start = Instant::now();
+        let wat = compile_source(source, name, TargetKind::Wasi)?;
+        let ashfire = start.elapsed();
+        let wasm = wat::parse_bytes(&wat)?;
+        let assemble = start.elapsed() - ashfire;
+        let module = Module::new(&self.engine, &wasm)?;
+        let codegen = start.elapsed() - ashfire - assemble;
+
+        let compiled = Arc::new(Compiled {
+            key,
+            wasm_size: wasm.len(),
+            wat,
+            module,
+            phases: vec![
+                ("ashfire", ashfire),
+                ("wat", assemble),
+                ("codegen", codegen),
+            ],
+        });
+        let evicted = self.cache().insert(compiled.clone());
+        self.stats.evicted(evicted);
+        Ok(compiled)
+    }
+
+    /// Assembles hand-written WAT, bypassing both ashfire and the cache.
+    pub fn assemble(&self, wat: &str) -> Result<Compiled> {
+        let start = Instant::now();
+        let wasm = wat::parse_str(wat)?;
+        let assemble = start.elapsed();
+        let module = Module::new(&self.engine, &wasm)?;
+        let codegen = start.elapsed() - assemble;
+
+        Ok(Compiled {
+            key: cache::key(wat, ""),
+            wat: wat.as_bytes().to_vec(),
+            wasm_size: wasm.len(),
+            module,
+            phases: vec![("wat", assemble), ("codegen", codegen)],
+        })
+    }
+
+    /// Compiles a snippet for a specific target, bypassing the cache.
+    pub fn compile_for(&self, source: &str, name: &str, target: TargetKind) -> Result<Module> {
+        let wat = compile_source(source, name, target)?;
+        Module::new(&self.engine, wat::parse_bytes(&wat)?)
+    }
+
+    pub fn run(
+        &self,
+        module: &Module,
+        pipes: &Pipes,
+        sandbox: &Sandbox,
+        limits: Limits,
+    ) -> Result<RunStats> {
+        if sandbox.safe {
+            return self.run_safe(module, pipes, limits);
+        }
+
+        sandbox.check(module)?;
+
+        // Args, env vars and preopened directories are never provided, so the disabled
+        // capabilities are enforced by rejecting modules that import them
+        let stderr = pipes.stderr.as_ref().unwrap_or(&pipes.stdout);
+        let mut wasi = WasiCtxBuilder::new()
+            .stdout(Box::new(WritePipe::new(pipes.stdout.clone())))
+            .stderr(Box::new(WritePipe::new(stderr.clone())))
+            .build();
+
+        if let Some(seed) = sandbox.seed {
+            wasi.random = Box::new(StdRng::seed_from_u64(seed));
+        }
+
+        let host = Host {
+            wasi,
+            memory: PeakMemory::new(limits),
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.memory);
+
+        let mut linker = self.linker.clone();
+        for import in module.imports() {
+            if linker.get_by_import(&mut store, &import).is_none() {
+                bail!(
+                    "module requires unavailable import '{}' from '{}'",
+                    import.name(),
+                    import.module()
+                );
+            }
+        }
+
+        measure(&mut store, &mut linker, module, pipes, limits, |host| {
+            &host.memory
+        })
+        .map_err(|err| with_partial_output(err, limits, pipes))
+    }
+
+    /// Runs without WASI at all, so the program can only compute.
+    fn run_safe(&self, module: &Module, pipes: &Pipes, limits: Limits) -> Result<RunStats> {
+        if let Some(import) = module.imports().next() {
+            bail!(
+                "safe mode is on, so programs can't do any I/O, but this one imports `{}::{}`",
+                import.module(),
+                import.name()
+            );
+        }
+
+        let mut store = Store::new(&self.engine, PeakMemory::new(limits));
+        store.limiter(|memory| memory);
+
+        let mut linker = Linker::new(&self.engine);
+        let stats = measure(&mut store, &mut linker, module, pipes, limits, |memory| {
+            memory
+        })
+        .map_err(|err| with_partial_output(err, limits, pipes))?;
+
+        Ok(RunStats {
+            safe: true,
+            ..stats
+        })
+    }
+
+    pub fn cached(&self, key: u64) -> Option<Arc<Compiled>> {
+        self.cache().get(key)
+    }
+
+    pub fn evict(&self, key: u64) -> bool {
+        let evicted = self.cache().remove(key).is_some();
+        if evicted {
+            self.stats.evicted(1);
+        }
+        evicted
+    }
+
+    pub fn cache_stats(&self) -> String {
+        self.stats.describe(self.cache().len())
+    }
+
+    pub fn latency(&self) -> &Latency {
+        &self.latency
+    }
+
+    #[cfg(test)]
+    pub(crate) fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    fn cache(&self) -> MutexGuard<'_, ModuleCache> {
+        self.cache.lock().expect("Module cache lock poisoned")
+    }
+}
+
+/// Instantiates the module and calls its default export, accounting for what it used.
+///
+/// A call to `proc_exit` ends the run normally, with its status reported.
+fn measure<T>(
+    store: &mut Store<T>,
+    linker: &mut Linker<T>,
+    module: &Module,
+    pipes: &Pipes,
+    limits: Limits,
+    memory: fn(&T) -> &PeakMemory,
+) -> Result<RunStats> {
+    let start = Instant::now();
+    store.add_fuel(FUEL)?;
+
+    // Checking in on every tick lets a cancelled run stop right away, not only at its timeout
+    let cancel = pipes.cancel.clone();
+    let mut remaining = limits.deadline();
+    store.set_epoch_deadline(1);
+    store.epoch_deadline_callback(move |_| {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(Cancelled.into());
+        }
+
+        if let Some(remaining) = remaining.as_mut() {
+            *remaining -= 1;
+            if *remaining == 0 {
+                return Err(Trap::Interrupt.into());
+            }
+        }
+        Ok(1)
+    });
+
+    // Memories are created on instantiation, which the limit applies to as well
+    let result = linker.module(&mut *store, "", module).and_then(|linker| {
+        linker
+            .get_default(&mut *store, "")?
+            .typed::<(), ()>(&*store)?
+            .call(&mut *store, ())
+    });
+
+    let exit = match result {
+        Ok(()) => 0,
+        Err(_) if memory(store.data()).exceeded => {
+            return Err(MemoryLimit {
+                pages: limits.memory_pages,
+            }
+            .into())
+        }
+        Err(err) => match err.downcast_ref::<I32Exit>() {
+            Some(exit) => exit.0,
+            None => return Err(err),
+        },
+    };
+
+    Ok(RunStats {
+        wall: start.elapsed(),
+        fuel: store.fuel_consumed().unwrap_or_default(),
+        peak_pages: memory(store.data()).peak / PAGE_SIZE,
+        exit,
+        safe: false,
+    })
+}
+
+/// A run stopped by its timeout, with the output it wrote until then.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub after: Duration,
+    pub output: Vec<u8>,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "program did not terminate within {:?}", self.after)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// A run stopped for growing its memory past the limit.
+#[derive(Debug)]
+pub struct MemoryLimit {
+    pub pages: usize,
+}
+
+impl fmt::Display for MemoryLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "program tried to use more than {} of memory",
+            show_pages(self.pages)
+        )
+    }
+}
+
+impl std::error::Error for MemoryLimit {}
+
+/// A number of pages in MiB, the unit they are easiest to picture in.
+pub fn show_pages(pages: usize) -> String {
+    format!("{} MiB", pages * PAGE_SIZE / (1024 * 1024))
+}
+
+/// A run stopped on request, before it finished or timed out.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("cancelled by user")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// What a program printed before it trapped, kept as context of the trap.
+#[derive(Debug)]
+pub struct PartialOutput(pub Vec<u8>);
+
+impl fmt::Display for PartialOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "program crashed after printing {} bytes", self.0.len())
+    }
+}
+
+/// Programs that loop forever or crash often print along the way, which is worth showing.
+fn with_partial_output(err: anyhow::Error, limits: Limits, pipes: &Pipes) -> anyhow::Error {
+    let output = pipes.stdout.contents();
+    match (err.downcast_ref::<Trap>(), limits.timeout) {
+        (Some(Trap::Interrupt), Some(after)) => TimedOut { after, output }.into(),
+        (Some(_), _) if !output.is_empty() => err.context(PartialOutput(output)),
+        _ => err,
+    }
+}
+
+/// Resources used by a run.
+#[derive(Clone, Copy)]
+pub struct RunStats {
+    pub wall: Duration,
+    pub fuel: u64,
+    pub peak_pages: usize,
+    pub exit: i32,
+    /// Whether the run had no WASI at all, which is told apart from what the program printed.
+    pub safe: bool,
+}
+
+struct Host {
+    wasi: WasiCtx,
+    memory: PeakMemory,
+}
+
+/// Lets memories grow up to the limit, remembering the largest size one reached.
+struct PeakMemory {
+    peak: usize,
+    max: usize,
+    exceeded: bool,
+}
+
+impl PeakMemory {
+    fn new(limits: Limits) -> Self {
+        Self {
+            peak: 0,
+            max: limits.memory_pages * PAGE_SIZE,
+            exceeded: false,
+        }
+    }
+}
+
+impl ResourceLimiter for PeakMemory {
+    fn memory_growing(&mut self, _: usize, desired: usize, _: Option<usize>) -> Result<bool> {
+        // Refusing would only make `memory.grow` return -1, which programs rarely check,
+        // so the run is stopped instead
+        if desired > self.max {
+            self.exceeded = true;
+            bail!("memory limit exceeded");
+        }
+
+        self.peak = self.peak.max(desired);
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, _: u32, _: u32, _: Option<u32>) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// What the host allows a run to use.
+#[derive(Clone, Default)]
+pub struct Sandbox {
+    pub disabled: BTreeSet<Capability>,
+    /// Runs without any WASI, overriding `disabled`.
+    pub safe: bool,
+    /// Seeds the random source, so that runs can be reproduced.
+    pub seed: Option<u64>,
+}
+
+impl Sandbox {
+    pub fn with_seed(&self, seed: Option<u64>) -> Self {
+        Self {
+            seed,
+            ..self.clone()
+        }
+    }
+
+    /// What `run` wires into a program's WASI context under this sandbox.
+    pub fn describe(&self) -> String {
+        if self.safe {
+            return "safe mode: on, programs get no WASI at all and can only compute".to_string();
+        }
+
+        let mut lines = vec![
+            "safe mode: off".to_string(),
+            "stdout, stderr: captured".to_string(),
+        ];
+        for capability in Capability::ALL {
+            let state = if self.disabled.contains(&capability) {
+                "disabled"
+            } else {
+                "enabled"
+            };
+            let provided = match capability {
+                Capability::Fs => "no preopened directories".to_string(),
+                Capability::Clock => "the host clocks".to_string(),
+                Capability::Random => match self.seed {
+                    Some(seed) => format!("seeded with {seed}"),
+                    None => "the host's randomness, unless `--seed` is given".to_string(),
+                },
+                Capability::Env => "no args and no env vars".to_string(),
+            };
+            lines.push(format!("{capability}: {state}, {provided}"));
+        }
+
+        lines.join("\n")
+    }
+
+    fn check(&self, module: &Module) -> Result<()> {
+        for import in module.imports() {
+            if import.module() != WASI_MODULE {
+                continue;
+            }
+
+            match Capability::of_import(import.name()) {
+                Some(capability) if self.disabled.contains(&capability) => {
+                    bail!("capability '{capability}' is disabled on this server")
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+    Fs,
+    Clock,
+    Random,
+    Env,
+}
+
+impl Capability {
+    pub const ALL: [Self; 4] = [Self::Fs, Self::Clock, Self::Random, Self::Env];
+
+    /// The capability a WASI function belongs to, if any.
+    fn of_import(name: &str) -> Option<Self> {
+        match name {
+            "clock_res_get" | "clock_time_get" => Some(Self::Clock),
+            "random_get" => Some(Self::Random),
+            "args_get" | "args_sizes_get" | "environ_get" | "environ_sizes_get" => Some(Self::Env),
+            "fd_prestat_get" | "fd_prestat_dir_name" | "fd_readdir" => Some(Self::Fs),
+            _ if name.starts_with("path_") => Some(Self::Fs),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Fs => "fs",
+            Self::Clock => "clock",
+            Self::Random => "random",
+            Self::Env => "env",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::ALL
+            .into_iter()
+            .find(|capability| capability.to_string() == s)
+        {
+            Some(capability) => Ok(capability),
+            None => bail!("Unknown capability `{s}`, expected one of: fs, clock, random, env"),
+        }
+    }
+}
+
+/// The output pipes of a run, which can be read while the program is still executing.
+#[derive(Clone)]
+pub struct Pipes {
+    pub stdout: SharedBuffer,
+    pub stderr: Option<SharedBuffer>,
+    /// Set from outside to stop the run at the next epoch tick.
+    pub cancel: Arc<AtomicBool>,
+}
+
+impl Pipes {
+    /// Captures stdout and stderr separately, unless `merge_streams` is set, in which case
+    /// both are written in order to stdout.
+    pub fn new(merge_streams: bool) -> Self {
+        Self {
+            stdout: SharedBuffer::default(),
+            stderr: (!merge_streams).then(SharedBuffer::default),
+            cancel: Arc::default(),
+        }
+    }
+
+    pub fn stderr_contents(&self) -> Vec<u8> {
+        self.stderr
+            .as_ref()
+            .map(SharedBuffer::contents)
+            .unwrap_or_default()
+    }
+}
+
+/// An in-memory pipe whose clones all write to the same buffer.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().expect("Pipe lock poisoned").clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .expect("Pipe lock poisoned")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+/// Lists the module's exports as a table of name, kind and signature.
+pub fn describe_exports(module: &Module) -> String {
+    let rows: Vec<_> = module
+        .exports()
+        .map(|export| {
+            let (kind, signature) = describe_type(export.ty());
+            (export.name(), kind, signature)
+        })
+        .collect();
+
+    if rows.is_empty() {
+        return "(no exports)".to_string();
+    }
+
+    let width = rows.iter().map(|(name, ..)| name.len()).max().unwrap_or(0);
+    rows.iter()
+        .map(|(name, kind, signature)| format!("{name:width$}  {kind:6}  {signature}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn describe_type(ty: ExternType) -> (&'static str, String) {
+    match ty {
+        ExternType::Func(func) => {
+            let params = join_types(func.params());
+            let results = join_types(func.results());
+            ("func", format!("({params}) -> ({results})"))
+        }
+        ExternType::Global(global) => {
+            let mutability = match global.mutability() {
+                Mutability::Const => "const",
+                Mutability::Var => "mut",
+            };
+            ("global", format!("{mutability} {}", global.content()))
+        }
+        ExternType::Table(table) => (
+            "table",
+            format!("{} min {}", table.element(), table.minimum()),
+        ),
+        ExternType::Memory(memory) => ("memory", format!("min {} pages", memory.minimum())),
+    }
+}
+
+fn join_types(types: impl Iterator<Item = ValType>) -> String {
+    types
+        .map(|ty| ty.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The ashfire targets this host knows how to run.
+#[derive(Clone, Copy)]
+pub enum TargetKind {
+    Wasi,
+}
+
+impl TargetKind {
+    pub const ALL: [Self; 1] = [Self::Wasi];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Wasi => "wasi",
+        }
+    }
+
+    fn target(self) -> Target {
+        match self {
+            Self::Wasi => Target::Wasi,
+        }
+    }
+}
+
+/// A compilation that didn't finish in time, told apart from a run timing out.
+#[derive(Debug)]
+pub struct CompileTimeout(pub Duration);
+
+impl fmt::Display for CompileTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "compilation timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for CompileTimeout {}
+
+fn compile_source(source: &str, name: &str, target: TargetKind) -> Result<Vec<u8>> {
+    let reader = &mut BufReader::new(source.as_bytes());
+    let mut writer = BufWriter::new(vec![]);
+
+    ashfire::compile_buffer(name, reader, &mut writer, target.target(), true)?;
+
+    Ok(writer.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_wat(wat: &str) -> (Result<RunStats>, Pipes) {
+        run_wat_in(wat, &Sandbox::default())
+    }
+
+    fn run_wat_in(wat: &str, sandbox: &Sandbox) -> (Result<RunStats>, Pipes) {
+        let runtime = Runtime::new().unwrap();
+        let compiled = runtime.assemble(wat).unwrap();
+        let pipes = Pipes::new(false);
+        let limits = Limits::for_command(limits::Command::Eval);
+        let result = runtime.run(&compiled.module, &pipes, sandbox, limits);
+        (result, pipes)
+    }
+
+    #[test]
+    fn names_unavailable_imports() {
+        let (result, _) = run_wat(
+            r#"(module
+                (import "env" "foo" (func))
+                (func (export "_start")))"#,
+        );
+
+        let err = result.err().expect("the import can't be satisfied");
+        assert!(err.to_string().contains("'foo' from 'env'"), "{err}");
+    }
+
+    #[test]
+    fn leaves_the_output_of_safe_runs_alone() {
+        let sandbox = Sandbox {
+            safe: true,
+            ..Sandbox::default()
+        };
+        let (result, pipes) = run_wat_in(r#"(module (func (export "_start")))"#, &sandbox);
+
+        assert!(result.unwrap().safe);
+        assert!(pipes.stdout.contents().is_empty());
+    }
+
+    #[test]
+    fn stops_growing_past_the_memory_limit() {
+        let (result, _) = run_wat(
+            r#"(module
+                (memory 1)
+                (func (export "_start")
+                    (drop (memory.grow (i32.const 2000)))))"#,
+        );
+
+        let err = result.err().expect("the memory can't grow that much");
+        let limit = err
+            .downcast_ref::<MemoryLimit>()
+            .unwrap_or_else(|| panic!("{err}"));
+        assert_eq!(limit.pages, 1024);
+        assert_eq!(
+            err.to_string(),
+            "program tried to use more than 64 MiB of memory"
+        );
+    }
+
+    #[test]
+    fn refuses_initial_memory_past_the_limit() {
+        let (result, _) = run_wat(r#"(module (memory 2000) (func (export "_start")))"#);
+
+        let err = result.err().expect("the memory can't be that large");
+        assert!(err.downcast_ref::<MemoryLimit>().is_some(), "{err}");
+    }
+
+    #[test]
+    fn records_peak_memory_within_the_limit() {
+        let (result, _) = run_wat(
+            r#"(module
+                (memory 1)
+                (func (export "_start")
+                    (drop (memory.grow (i32.const 3)))))"#,
+        );
+
+        assert_eq!(result.unwrap().peak_pages, 4);
+    }
+}
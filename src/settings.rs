@@ -0,0 +1,264 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serenity::{
+    model::prelude::{ChannelId, GuildId},
+    utils::parse_channel,
+};
+
+use crate::{
+    format::{self, Templates},
+    i18n,
+    runtime::{Capability, Sandbox},
+};
+
+/// Output lines shown by default, before the rest is cut.
+const MAX_LINES: usize = 50;
+const STREAM_INTERVAL: Duration = Duration::from_millis(1500);
+/// Edits any faster than this would run into Discord's rate limits.
+const MIN_STREAM_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Per-guild settings, changed with `?config`.
+#[derive(Clone)]
+pub struct GuildSettings {
+    /// Replaces the default command prefix in the guild.
+    pub prefix: Option<String>,
+    pub permalink: bool,
+    /// Shows the compiler version under each result.
+    pub show_compiler: bool,
+    pub locale: String,
+    pub edit_mode: EditMode,
+    /// Deletes the previous reply when an edit posts a new one.
+    pub delete_old_reply: bool,
+    /// Deletes the reply when an edit removes the code block.
+    pub retract_on_clear: bool,
+    pub max_lines: usize,
+    /// How long `--stream` waits between edits, only showing the latest output in each.
+    pub stream_interval: Duration,
+    pub sandbox: Sandbox,
+    pub templates: Templates,
+    pub safe_channels: HashSet<ChannelId>,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            permalink: false,
+            show_compiler: false,
+            locale: i18n::DEFAULT_LOCALE.to_string(),
+            edit_mode: EditMode::default(),
+            delete_old_reply: false,
+            retract_on_clear: true,
+            max_lines: MAX_LINES,
+            stream_interval: STREAM_INTERVAL,
+            sandbox: Sandbox::default(),
+            templates: Templates::default(),
+            safe_channels: HashSet::new(),
+        }
+    }
+}
+
+impl GuildSettings {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "prefix" => self.prefix = parse_prefix(value)?,
+            "permalink" => self.permalink = parse_bool(value)?,
+            "show_compiler" => self.show_compiler = parse_bool(value)?,
+            "locale" => {
+                if !i18n::is_known(value) {
+                    bail!("Unknown locale `{value}`");
+                }
+                self.locale = value.to_string();
+            }
+            "edit_mode" => self.edit_mode = value.parse()?,
+            "delete_old_reply" => self.delete_old_reply = parse_bool(value)?,
+            "retract_on_clear" => self.retract_on_clear = parse_bool(value)?,
+            "safe" => self.sandbox.safe = parse_bool(value)?,
+            "max_lines" => {
+                self.max_lines = match value.parse() {
+                    Ok(lines) if lines > 0 => lines,
+                    _ => bail!("Expected a positive number of lines, got `{value}`"),
+                }
+            }
+            "stream_interval" => {
+                self.stream_interval = match value.trim_end_matches("ms").parse() {
+                    Ok(millis) if Duration::from_millis(millis) >= MIN_STREAM_INTERVAL => {
+                        Duration::from_millis(millis)
+                    }
+                    _ => bail!(
+                        "Expected an interval of at least {}ms, got `{value}`",
+                        MIN_STREAM_INTERVAL.as_millis()
+                    ),
+                }
+            }
+            "safe_channel" => {
+                self.safe_channels.insert(parse_channel_id(value)?);
+            }
+            "unsafe_channel" => {
+                self.safe_channels.remove(&parse_channel_id(value)?);
+            }
+            "disable" => {
+                self.sandbox.disabled.insert(value.parse()?);
+            }
+            "template.result" | "template.error" => {
+                format::validate_template(value)?;
+                let template = value.replace("\\n", "\n");
+                if key == "template.result" {
+                    self.templates.result = Some(template);
+                } else {
+                    self.templates.error = Some(template);
+                }
+            }
+            "enable" => {
+                self.sandbox.disabled.remove(&value.parse::<Capability>()?);
+            }
+            _ => bail!("Unknown setting `{key}`"),
+        }
+
+        Ok(())
+    }
+
+    /// Applies the channel's own settings on top of the guild ones.
+    pub fn in_channel(mut self, channel: ChannelId) -> Self {
+        self.sandbox.safe |= self.safe_channels.contains(&channel);
+        self
+    }
+
+    pub fn describe(&self) -> String {
+        let disabled: Vec<_> = self
+            .sandbox
+            .disabled
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let disabled = if disabled.is_empty() {
+            "none".to_string()
+        } else {
+            disabled.join(", ")
+        };
+
+        let safe_channels: Vec<_> = self
+            .safe_channels
+            .iter()
+            .map(|channel| format!("<#{channel}>"))
+            .collect();
+        let safe_channels = if safe_channels.is_empty() {
+            "none".to_string()
+        } else {
+            safe_channels.join(", ")
+        };
+
+        [
+            format!("prefix = {}", self.prefix.as_deref().unwrap_or("default")),
+            format!("permalink = {}", on_off(self.permalink)),
+            format!("show_compiler = {}", on_off(self.show_compiler)),
+            format!("edit_mode = {}", self.edit_mode),
+            format!("delete_old_reply = {}", on_off(self.delete_old_reply)),
+            format!("retract_on_clear = {}", on_off(self.retract_on_clear)),
+            format!("max_lines = {}", self.max_lines),
+            format!("stream_interval = {}ms", self.stream_interval.as_millis()),
+            format!("safe = {}", on_off(self.sandbox.safe)),
+            format!("safe channels = {safe_channels}"),
+            format!("disabled capabilities = {disabled}"),
+            format!("locale = {}", self.locale),
+            format!(
+                "template.result = {:?}",
+                self.templates.result(&self.locale)
+            ),
+            format!("template.error = {:?}", self.templates.error(&self.locale)),
+        ]
+        .join("\n")
+    }
+}
+
+/// How the result of an edited snippet is shown.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum EditMode {
+    /// Updates the existing reply.
+    #[default]
+    InPlace,
+    /// Posts a new reply, keeping the previous one visible.
+    NewReply,
+}
+
+impl fmt::Display for EditMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InPlace => f.write_str("in_place"),
+            Self::NewReply => f.write_str("new_reply"),
+        }
+    }
+}
+
+impl FromStr for EditMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "in_place" => Ok(Self::InPlace),
+            "new_reply" => Ok(Self::NewReply),
+            _ => bail!("Unknown edit mode `{s}`, expected one of: in_place, new_reply"),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => bail!("Expected `on` or `off`, got `{value}`"),
+    }
+}
+
+/// Accepts a prefix, or `default` to go back to the global one.
+fn parse_prefix(value: &str) -> Result<Option<String>> {
+    if value == "default" {
+        return Ok(None);
+    }
+
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '`') {
+        bail!("A prefix can't be empty or contain spaces or backticks, got `{value}`");
+    }
+
+    Ok(Some(value.to_string()))
+}
+
+/// Accepts a channel mention or a raw id.
+fn parse_channel_id(value: &str) -> Result<ChannelId> {
+    let id = parse_channel(value).or_else(|| value.parse().ok());
+    id.map(ChannelId)
+        .ok_or_else(|| anyhow!("Expected a channel, got `{value}`"))
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+#[derive(Default)]
+pub struct Settings {
+    guilds: HashMap<GuildId, GuildSettings>,
+}
+
+impl Settings {
+    /// Returns the settings in effect for a guild, or the defaults outside of one.
+    pub fn get(&self, guild: Option<GuildId>) -> GuildSettings {
+        guild
+            .and_then(|guild| self.guilds.get(&guild))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn guild_mut(&mut self, guild: GuildId) -> &mut GuildSettings {
+        self.guilds.entry(guild).or_default()
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use serenity::{client::bridge::gateway::ShardManager, prelude::Mutex};
+use tracing::{info, warn};
+
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// Counts running evaluations and stops new ones from starting once shutdown begins.
+#[derive(Default)]
+pub struct InFlight {
+    stopping: AtomicBool,
+    running: AtomicUsize,
+}
+
+impl InFlight {
+    pub fn enter(self: &Arc<Self>) -> Option<InFlightGuard> {
+        if self.stopping.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        self.running.fetch_add(1, Ordering::SeqCst);
+        Some(InFlightGuard(self.clone()))
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+pub struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits for a shutdown signal, drains running evaluations and stops every shard.
+pub async fn on_signal(in_flight: Arc<InFlight>, shard_manager: Arc<Mutex<ShardManager>>) {
+    wait_for_signal().await;
+
+    in_flight.stopping.store(true, Ordering::SeqCst);
+    let pending = in_flight.running();
+    info!("Shutting down, waiting on {} evaluations", pending);
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while in_flight.running() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL).await;
+    }
+
+    let abandoned = in_flight.running();
+    info!("Drained {} evaluations", pending.saturating_sub(abandoned));
+    if abandoned > 0 {
+        warn!("Abandoning {} evaluations still running", abandoned);
+    }
+
+    shard_manager.lock().await.shutdown_all().await;
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serenity::{
+    http::HttpError,
+    model::prelude::{ChannelId, MessageId, UserId},
+    prelude::{RwLock, SerenityError},
+};
+use tracing::info;
+
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const CAPACITY: usize = 1000;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Discord's error code for a message that doesn't exist, or no longer does.
+const UNKNOWN_MESSAGE: isize = 10008;
+
+pub struct Tracked {
+    pub reply: MessageId,
+    pub author: UserId,
+    pub channel: ChannelId,
+    pub created: Instant,
+    pub pinned: bool,
+}
+
+impl Tracked {
+    pub fn new(reply: MessageId, author: UserId, channel: ChannelId) -> Self {
+        Self {
+            reply,
+            author,
+            channel,
+            created: Instant::now(),
+            pinned: false,
+        }
+    }
+}
+
+/// Maps evaluated messages to the bot reply holding their result.
+#[derive(Default)]
+pub struct Tracker {
+    entries: HashMap<MessageId, Tracked>,
+}
+
+impl Tracker {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, source: &MessageId) -> Option<&Tracked> {
+        self.entries.get(source)
+    }
+
+    pub fn source_of(&self, reply: MessageId) -> Option<MessageId> {
+        self.entries
+            .iter()
+            .find(|(_, entry)| entry.reply == reply)
+            .map(|(source, _)| *source)
+    }
+
+    /// The most recent evaluation by `author` in `channel`.
+    pub fn latest(&self, author: UserId, channel: ChannelId) -> Option<MessageId> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.author == author && entry.channel == channel)
+            .max_by_key(|(_, entry)| entry.created)
+            .map(|(source, _)| *source)
+    }
+
+    pub fn by_reply_mut(&mut self, reply: MessageId) -> Option<&mut Tracked> {
+        self.entries.values_mut().find(|entry| entry.reply == reply)
+    }
+
+    pub fn insert(&mut self, source: MessageId, entry: Tracked) {
+        self.prune();
+        self.entries.insert(source, entry);
+    }
+
+    pub fn remove(&mut self, source: &MessageId) -> Option<Tracked> {
+        self.entries.remove(source)
+    }
+
+    /// Updates the entry of `source` once sending or editing its reply is done, which is the
+    /// only way replies get tracked, so that the tracker never points at one that wasn't sent.
+    ///
+    /// A delivered reply replaces the tracked one, the entry keeping its age and pin. A reply
+    /// found to be gone is forgotten, as it would fail again on every edit, while any other
+    /// failure leaves the tracker as it was, since it may well not happen next time.
+    pub fn settle(&mut self, source: MessageId, delivered: Result<Tracked, &SerenityError>) {
+        match delivered {
+            Ok(entry) => match self.entries.get_mut(&source) {
+                Some(tracked) => tracked.reply = entry.reply,
+                None => self.insert(source, entry),
+            },
+            Err(err) if is_gone(err) => {
+                self.remove(&source);
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Evicts expired entries, pinned ones excepted.
+    pub fn expire(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, entry| entry.pinned || entry.created.elapsed() < TTL);
+        before - self.entries.len()
+    }
+
+    /// Evicts expired entries, then the oldest ones while at capacity.
+    /// Pinned entries are never evicted.
+    pub fn prune(&mut self) -> usize {
+        let before = self.entries.len();
+        self.expire();
+
+        while self.entries.len() >= CAPACITY {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.pinned)
+                .min_by_key(|(_, entry)| entry.created)
+                .map(|(id, _)| *id);
+
+            match oldest {
+                Some(id) => self.entries.remove(&id),
+                None => break,
+            };
+        }
+
+        before - self.entries.len()
+    }
+}
+
+/// Whether a request failed because the message it was about is gone.
+pub fn is_gone(err: &SerenityError) -> bool {
+    let SerenityError::Http(err) = err else {
+        return false;
+    };
+
+    matches!(
+        &**err,
+        HttpError::UnsuccessfulRequest(response) if response.error.code == UNKNOWN_MESSAGE
+    )
+}
+
+/// Periodically evicts expired entries, which would otherwise only go away on the next insert.
+pub async fn sweep(tracker: Arc<RwLock<Tracker>>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let removed = tracker.write().await.expire();
+        info!("Swept {} expired tracked messages", removed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::http::error::ErrorResponse;
+
+    use super::*;
+
+    const SOURCE: MessageId = MessageId(1);
+    const REPLY: MessageId = MessageId(2);
+    const NEW_REPLY: MessageId = MessageId(3);
+
+    /// The error Discord answers a request with, `code` telling what went wrong.
+    async fn discord_error(code: isize) -> SerenityError {
+        let body = format!(r#"{{"code": {code}, "message": "error {code}", "errors": {{}}}}"#);
+        let response = http::Response::builder().status(404).body(body).unwrap();
+        let response = ErrorResponse::from_response(response.into()).await;
+        HttpError::UnsuccessfulRequest(response).into()
+    }
+
+    fn entry(reply: MessageId) -> Tracked {
+        Tracked::new(reply, UserId(4), ChannelId(5))
+    }
+
+    fn tracking(reply: MessageId) -> Tracker {
+        let mut tracker = Tracker::default();
+        tracker.insert(SOURCE, entry(reply));
+        tracker
+    }
+
+    #[tokio::test]
+    async fn tells_gone_messages_from_other_failures() {
+        assert!(is_gone(&discord_error(UNKNOWN_MESSAGE).await));
+
+        // Missing Permissions, which may be granted later
+        assert!(!is_gone(&discord_error(50013).await));
+        assert!(!is_gone(&SerenityError::Other("gateway hiccup")));
+    }
+
+    #[test]
+    fn tracks_delivered_replies() {
+        let mut tracker = Tracker::default();
+        tracker.settle(SOURCE, Ok(entry(REPLY)));
+
+        assert_eq!(tracker.get(&SOURCE).map(|entry| entry.reply), Some(REPLY));
+    }
+
+    #[test]
+    fn keeps_entries_when_a_new_reply_is_delivered() {
+        let mut tracker = tracking(REPLY);
+        tracker.by_reply_mut(REPLY).unwrap().pinned = true;
+        tracker.settle(SOURCE, Ok(entry(NEW_REPLY)));
+
+        let tracked = tracker.get(&SOURCE).unwrap();
+        assert_eq!(tracked.reply, NEW_REPLY);
+        assert!(tracked.pinned);
+    }
+
+    #[tokio::test]
+    async fn tracks_nothing_when_a_reply_fails() {
+        let mut tracker = Tracker::default();
+        tracker.settle(SOURCE, Err(&SerenityError::Other("gateway hiccup")));
+        tracker.settle(SOURCE, Err(&discord_error(UNKNOWN_MESSAGE).await));
+
+        assert_eq!(tracker.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_entry_when_an_edit_fails() {
+        let mut tracker = tracking(REPLY);
+        tracker.settle(SOURCE, Err(&SerenityError::Other("gateway hiccup")));
+        tracker.settle(SOURCE, Err(&discord_error(50013).await));
+
+        assert_eq!(tracker.get(&SOURCE).map(|entry| entry.reply), Some(REPLY));
+    }
+
+    #[tokio::test]
+    async fn forgets_replies_that_are_gone() {
+        let mut tracker = tracking(REPLY);
+        tracker.settle(SOURCE, Err(&discord_error(UNKNOWN_MESSAGE).await));
+
+        assert!(tracker.get(&SOURCE).is_none());
+    }
+}